@@ -0,0 +1,162 @@
+use git2::{Cred, CredentialType, RemoteCallbacks};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf};
+
+/// libgit2 keeps asking for credentials as long as the callback returns some,
+/// so give up after this many rejected attempts.
+const MAX_ATTEMPTS: usize = 3;
+
+/// How to authenticate against a repository's remote.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Credentials {
+    /// Use the keys loaded into the running ssh-agent.
+    SshAgent { username: Option<String> },
+    /// Use an explicit private key, optionally protected by a passphrase
+    /// stored in `passphrase_file`.
+    SshKey {
+        username: Option<String>,
+        private_key: PathBuf,
+        public_key: Option<PathBuf>,
+        passphrase_file: Option<PathBuf>,
+    },
+    /// Ask the git credential helper configured in gitconfig.
+    CredentialHelper,
+    /// Send the token stored in the environment variable `env` as password.
+    Token {
+        env: String,
+        username: Option<String>,
+    },
+}
+
+fn username<'a>(configured: &'a Option<String>, from_url: Option<&'a str>) -> &'a str {
+    configured.as_deref().or(from_url).unwrap_or("git")
+}
+
+/// Produces the credential libgit2 asked for in a single callback invocation.
+fn credential(
+    credentials: &Credentials,
+    git_config: &git2::Config,
+    url: &str,
+    username_from_url: Option<&str>,
+    allowed: CredentialType,
+) -> Result<Cred, git2::Error> {
+    match credentials {
+        Credentials::SshAgent { username: user } | Credentials::SshKey { username: user, .. }
+            if allowed.contains(CredentialType::USERNAME) =>
+        {
+            Cred::username(username(user, username_from_url))
+        }
+        Credentials::SshAgent { username: user } => {
+            Cred::ssh_key_from_agent(username(user, username_from_url))
+        }
+        Credentials::SshKey {
+            username: user,
+            private_key,
+            public_key,
+            passphrase_file,
+        } => {
+            let passphrase = match passphrase_file {
+                Some(passphrase_file) => Some(
+                    fs::read_to_string(passphrase_file)
+                        .map_err(|error| {
+                            git2::Error::from_str(&format!(
+                                "cannot read passphrase file {:?}: {}",
+                                passphrase_file, error
+                            ))
+                        })?
+                        .trim_end()
+                        .to_string(),
+                ),
+                None => None,
+            };
+            Cred::ssh_key(
+                username(user, username_from_url),
+                public_key.as_deref(),
+                private_key,
+                passphrase.as_deref(),
+            )
+        }
+        Credentials::CredentialHelper => {
+            Cred::credential_helper(git_config, url, username_from_url)
+        }
+        Credentials::Token {
+            env: variable,
+            username: user,
+        } => {
+            let token = env::var(variable).map_err(|error| {
+                git2::Error::from_str(&format!("cannot read token from ${}: {}", variable, error))
+            })?;
+            Cred::userpass_plaintext(username(user, username_from_url), &token)
+        }
+    }
+}
+
+/// Builds the callbacks that answer libgit2's credential requests for
+/// `credentials`. Without credentials libgit2's defaults are kept.
+pub fn remote_callbacks<'a>(
+    credentials: Option<&'a Credentials>,
+    git_config: git2::Config,
+) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    if let Some(credentials) = credentials {
+        let mut attempts = 0;
+        callbacks.credentials(move |url, username_from_url, allowed| {
+            attempts += 1;
+            if attempts > MAX_ATTEMPTS {
+                return Err(git2::Error::new(
+                    git2::ErrorCode::Auth,
+                    git2::ErrorClass::Ssh,
+                    "authentication failed",
+                ));
+            }
+            debug!("Authenticating against {} ({:?})", url, allowed);
+            credential(credentials, &git_config, url, username_from_url, allowed)
+        });
+    }
+    callbacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_credentials() {
+        let credentials: Credentials = serde_json::from_str(
+            r#"{"type": "ssh_key", "private_key": "/home/me/.ssh/id_ed25519"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            credentials,
+            Credentials::SshKey {
+                username: None,
+                private_key: PathBuf::from("/home/me/.ssh/id_ed25519"),
+                public_key: None,
+                passphrase_file: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_token_credential() {
+        let git_config = git2::Config::new().unwrap();
+        let credentials = Credentials::Token {
+            env: "GIT_AUTO_FETCH_TEST_TOKEN".to_string(),
+            username: None,
+        };
+        let request = |credentials| {
+            credential(
+                credentials,
+                &git_config,
+                "https://example.com/repo.git",
+                None,
+                CredentialType::USER_PASS_PLAINTEXT,
+            )
+        };
+        assert!(request(&credentials).is_err());
+        env::set_var("GIT_AUTO_FETCH_TEST_TOKEN", "secret");
+        assert!(request(&credentials).unwrap().has_username());
+    }
+}
@@ -0,0 +1,92 @@
+mod git;
+mod libgit2;
+
+use crate::{
+    fetch::{FetchOutcome, RemoteOutcome},
+    progress::ProgressDisplay,
+    GitRepository,
+};
+use git::GitCli;
+use git2::Oid;
+use libgit2::Libgit2;
+use serde::{Deserialize, Serialize};
+
+/// Which implementation talks to the remotes of a repository.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    /// The libgit2 linked into this program.
+    Libgit2,
+    /// The `git` executable found on `PATH`.
+    Git,
+}
+
+impl Backend {
+    /// The backend that handles `repository`. Shallow and partial fetches
+    /// always use `git`, the linked libgit2 can't do them; an entry asking
+    /// for libgit2 anyway is rejected when the config is checked.
+    pub fn select(repository: &GitRepository) -> Self {
+        if repository.depth.is_some() || repository.unshallow || repository.filter.is_some() {
+            return Backend::Git;
+        }
+        repository.backend.unwrap_or(Backend::Libgit2)
+    }
+
+    pub fn implementation(self) -> &'static dyn FetchBackend {
+        match self {
+            Backend::Libgit2 => &Libgit2,
+            Backend::Git => &GitCli,
+        }
+    }
+}
+
+/// Clones, lists and fetches remotes on behalf of `fetch::handle_repository`.
+pub trait FetchBackend {
+    /// Clones `url` into `repository.local_path`, naming its remote `remote`,
+    /// and records the transfer in `outcome`.
+    fn clone(
+        &self,
+        repository: &GitRepository,
+        url: &str,
+        remote: &str,
+        progress: &ProgressDisplay,
+        outcome: &mut FetchOutcome,
+    ) -> Result<(), git2::Error>;
+
+    /// The refs the remote `remote` advertises, with the object each points
+    /// to.
+    fn list(
+        &self,
+        git_repository: &git2::Repository,
+        repository: &GitRepository,
+        remote: &str,
+    ) -> Result<Vec<(String, Oid)>, git2::Error>;
+
+    /// Fetches `refspecs` from the remote `outcome.name`, its configured
+    /// refspecs if there are none, and records the transfer and changed refs
+    /// in `outcome`.
+    fn fetch(
+        &self,
+        git_repository: &git2::Repository,
+        repository: &GitRepository,
+        refspecs: &[String],
+        progress: &ProgressDisplay,
+        outcome: &mut RemoteOutcome,
+    ) -> Result<(), git2::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let mut repository = GitRepository::default();
+        assert_eq!(Backend::select(&repository), Backend::Libgit2);
+        repository.backend = Some(Backend::Git);
+        assert_eq!(Backend::select(&repository), Backend::Git);
+        repository.backend = Some(Backend::Libgit2);
+        repository.depth = Some(1);
+        assert_eq!(Backend::select(&repository), Backend::Git);
+    }
+}
@@ -0,0 +1,494 @@
+use super::FetchBackend;
+use crate::{
+    auth::Credentials,
+    fetch::{self, FetchOutcome, RemoteOutcome, TagPolicy},
+    progress::{ProgressDisplay, TransferStats},
+    GitRepository,
+};
+use git2::{ErrorClass, ErrorCode, Oid};
+use log::debug;
+use std::{
+    ffi::OsString,
+    fs,
+    path::{self, Path},
+    process::{Command, Output},
+};
+
+/// Environment variable the token credential helper reads the username from.
+const TOKEN_USERNAME_VARIABLE: &str = "GIT_AUTO_FETCH_USERNAME";
+
+/// Environment variable the token credential helper reads the token from.
+const TOKEN_PASSWORD_VARIABLE: &str = "GIT_AUTO_FETCH_TOKEN";
+
+/// Quotes `value` for `sh`, e.g. `it's` becomes `'it'\''s'`.
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Options that make `git` authenticate with `credentials`. The ssh agent
+/// and the credential helper are git's defaults anyway.
+fn credential_args(
+    credentials: Option<&Credentials>,
+    command: &mut Command,
+) -> Result<(), git2::Error> {
+    match credentials {
+        None | Some(Credentials::SshAgent { username: None }) => (),
+        Some(Credentials::CredentialHelper) => (),
+        Some(Credentials::SshAgent {
+            username: Some(username),
+        }) => {
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!("ssh -l {}", shell_quote(username)),
+            );
+        }
+        Some(Credentials::SshKey {
+            passphrase_file: Some(_),
+            ..
+        }) => {
+            return Err(git2::Error::new(
+                ErrorCode::Auth,
+                ErrorClass::Ssh,
+                "the git executable can't use a passphrase_file",
+            ));
+        }
+        Some(Credentials::SshKey {
+            username,
+            private_key,
+            ..
+        }) => {
+            let mut ssh = format!(
+                "ssh -o IdentitiesOnly=yes -i {}",
+                shell_quote(&private_key.to_string_lossy())
+            );
+            if let Some(username) = username {
+                ssh.push_str(&format!(" -l {}", shell_quote(username)));
+            }
+            command.env("GIT_SSH_COMMAND", ssh);
+        }
+        Some(Credentials::Token { env, username }) => {
+            let token = std::env::var(env).map_err(|error| {
+                git2::Error::from_str(&format!("cannot read token from ${}: {}", env, error))
+            })?;
+            // The helper reads the username and token from the environment,
+            // so neither shows up in the process list nor reaches the shell.
+            let helper = format!(
+                "!f() {{ echo \"username=${}\"; echo \"password=${}\"; }}; f",
+                TOKEN_USERNAME_VARIABLE, TOKEN_PASSWORD_VARIABLE
+            );
+            command
+                .env(
+                    TOKEN_USERNAME_VARIABLE,
+                    username.as_deref().unwrap_or("git"),
+                )
+                .env(TOKEN_PASSWORD_VARIABLE, token)
+                .arg("-c")
+                .arg("credential.helper=")
+                .arg("-c")
+                .arg(format!("credential.helper={}", helper));
+        }
+    }
+    Ok(())
+}
+
+/// Turns the output of a failed `git` into an error `retry::is_transient`
+/// can judge.
+fn error_from_stderr(stderr: &str) -> git2::Error {
+    let message = stderr
+        .lines()
+        .rev()
+        .find(|line| line.starts_with("fatal:") || line.starts_with("error:"))
+        .or_else(|| stderr.lines().last())
+        .unwrap_or("git failed")
+        .trim();
+    let lower = stderr.to_lowercase();
+    // `unable to access '<url>': The requested URL returned error: 403`
+    let http_status = lower
+        .split("returned error: ")
+        .nth(1)
+        .and_then(|rest| rest.get(..3))
+        .and_then(|status| status.parse::<u16>().ok());
+    let (code, class) = if let Some(status @ 400..=499) = http_status {
+        match status {
+            401 | 403 => (ErrorCode::Auth, ErrorClass::Http),
+            404 => (ErrorCode::NotFound, ErrorClass::Http),
+            // Request timeouts and rate limits are worth retrying.
+            408 | 429 => (ErrorCode::GenericError, ErrorClass::Http),
+            _ => (ErrorCode::GenericError, ErrorClass::None),
+        }
+    } else if lower.contains("authentication failed") || lower.contains("permission denied") {
+        (ErrorCode::Auth, ErrorClass::Net)
+    } else if lower.contains("does not appear to be a git repository")
+        || lower.contains("not found")
+    {
+        (ErrorCode::NotFound, ErrorClass::Net)
+    } else if [
+        "could not resolve host",
+        "connection timed out",
+        "connection reset",
+        "connection refused",
+        "early eof",
+        "the remote end hung up",
+        "unable to access",
+    ]
+    .iter()
+    .any(|pattern| lower.contains(pattern))
+    {
+        (ErrorCode::GenericError, ErrorClass::Net)
+    } else {
+        (ErrorCode::GenericError, ErrorClass::None)
+    };
+    git2::Error::new(code, class, message)
+}
+
+/// Runs `git` with `args` in `directory` and returns its output if it
+/// succeeded.
+fn git(
+    repository: &GitRepository,
+    directory: &Path,
+    args: Vec<OsString>,
+) -> Result<Output, git2::Error> {
+    let mut command = Command::new("git");
+    command
+        .current_dir(directory)
+        .env("GIT_TERMINAL_PROMPT", "0");
+    credential_args(repository.credentials.as_ref(), &mut command)?;
+    command.args(&args);
+    debug!("Running git {:?} in {:?}", args, directory);
+    let output = command.output().map_err(|error| {
+        git2::Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Os,
+            format!("cannot run git: {}", error),
+        )
+    })?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(error_from_stderr(&String::from_utf8_lossy(&output.stderr)))
+    }
+}
+
+/// Parses the size git prints for a transfer, such as `59.34 KiB`.
+fn parse_size(size: &str) -> Option<usize> {
+    let (value, unit) = size.trim().split_once(' ')?;
+    let factor = match unit {
+        "bytes" | "byte" => 1.0,
+        "KiB" => 1024.0,
+        "MiB" => 1024.0 * 1024.0,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    Some((value.parse::<f64>().ok()? * factor) as usize)
+}
+
+/// Reads the totals from the last `Receiving objects` progress line git
+/// wrote to `stderr`, e.g. `Receiving objects: 100% (12/12), 59.34 KiB |
+/// 59.34 MiB/s, done.` The size is missing for tiny transfers.
+fn transfer_stats(stderr: &str) -> TransferStats {
+    let line = match stderr
+        .rsplit(['\r', '\n'])
+        .find_map(|line| line.trim().strip_prefix("Receiving objects:"))
+    {
+        Some(line) => line,
+        None => return TransferStats::default(),
+    };
+    let objects = line
+        .split_once('(')
+        .and_then(|(_, counts)| counts.split_once(')'))
+        .and_then(|(counts, _)| counts.split_once('/'))
+        .and_then(|(_, total)| total.parse().ok())
+        .unwrap_or_default();
+    let bytes = line
+        .split_once("), ")
+        .and_then(|(_, rest)| parse_size(rest.split(['|', ',']).next()?))
+        .unwrap_or_default();
+    TransferStats { objects, bytes }
+}
+
+/// Parses the `<oid>\t<ref>` lines of `git ls-remote`.
+fn parse_ls_remote(stdout: &str) -> Result<Vec<(String, Oid)>, git2::Error> {
+    stdout
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| match line.split_once('\t') {
+            Some((oid, name)) => Ok((name.to_string(), Oid::from_str(oid)?)),
+            None => Err(git2::Error::from_str(&format!(
+                "unexpected ls-remote output {:?}",
+                line
+            ))),
+        })
+        .collect()
+}
+
+/// Shallow and partial fetch options shared by clone and fetch.
+fn history_args(repository: &GitRepository, args: &mut Vec<OsString>) {
+    if let Some(depth) = repository.depth {
+        args.push(format!("--depth={}", depth).into());
+    }
+    if let Some(filter) = &repository.filter {
+        args.push(format!("--filter={}", filter).into());
+    }
+}
+
+/// Arguments to clone `url` into `local_path` with the remote `remote`.
+fn clone_args(
+    repository: &GitRepository,
+    local_path: &Path,
+    url: &str,
+    remote: &str,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        "clone".into(),
+        "--progress".into(),
+        "--origin".into(),
+        remote.into(),
+    ];
+    history_args(repository, &mut args);
+    if repository.tags == Some(TagPolicy::None) {
+        args.push("--no-tags".into());
+    }
+    args.push("--".into());
+    args.push(url.into());
+    args.push(local_path.into());
+    args
+}
+
+/// Arguments to fetch `refspecs` from `remote`; `shallow` says whether the
+/// repository is shallow now.
+fn fetch_args(
+    repository: &GitRepository,
+    remote: &str,
+    refspecs: &[String],
+    shallow: bool,
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["fetch".into(), "--progress".into()];
+    // git refuses to unshallow a complete repository.
+    if repository.unshallow && shallow {
+        args.push("--unshallow".into());
+    }
+    history_args(repository, &mut args);
+    if repository.prune || repository.prune_tags {
+        args.push("--prune".into());
+    }
+    match repository.tags {
+        Some(TagPolicy::All) => args.push("--tags".into()),
+        Some(TagPolicy::None) => args.push("--no-tags".into()),
+        Some(TagPolicy::Auto) | None => (),
+    }
+    args.push("--".into());
+    args.push(remote.into());
+    args.extend(refspecs.iter().map(OsString::from));
+    args
+}
+
+/// The directory to run `git` in for `git_repository`.
+fn git_directory(git_repository: &git2::Repository) -> &Path {
+    git_repository
+        .workdir()
+        .unwrap_or_else(|| git_repository.path())
+}
+
+/// Fetches by running the `git` executable, which understands everything
+/// the installed git does, including its own configuration. There is no
+/// live progress; the totals are read from git's output afterwards.
+pub struct GitCli;
+
+impl FetchBackend for GitCli {
+    fn clone(
+        &self,
+        repository: &GitRepository,
+        url: &str,
+        remote: &str,
+        _progress: &ProgressDisplay,
+        outcome: &mut FetchOutcome,
+    ) -> Result<(), git2::Error> {
+        // git runs in the parent directory, so a relative path must not be
+        // resolved against it a second time, and git won't create it.
+        let local_path = path::absolute(&repository.local_path).map_err(|error| {
+            git2::Error::from_str(&format!(
+                "cannot resolve {:?}: {}",
+                repository.local_path, error
+            ))
+        })?;
+        let directory = local_path.parent().unwrap_or_else(|| Path::new("/"));
+        fs::create_dir_all(directory).map_err(|error| {
+            git2::Error::from_str(&format!("cannot create {:?}: {}", directory, error))
+        })?;
+        let args = clone_args(repository, &local_path, url, remote);
+        let output = git(repository, directory, args)?;
+        outcome.clone_transfer = transfer_stats(&String::from_utf8_lossy(&output.stderr));
+        Ok(())
+    }
+
+    fn list(
+        &self,
+        git_repository: &git2::Repository,
+        repository: &GitRepository,
+        remote: &str,
+    ) -> Result<Vec<(String, Oid)>, git2::Error> {
+        let args = vec!["ls-remote".into(), "--".into(), remote.into()];
+        let output = git(repository, git_directory(git_repository), args)?;
+        parse_ls_remote(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn fetch(
+        &self,
+        git_repository: &git2::Repository,
+        repository: &GitRepository,
+        refspecs: &[String],
+        _progress: &ProgressDisplay,
+        outcome: &mut RemoteOutcome,
+    ) -> Result<(), git2::Error> {
+        let before = fetch::ref_targets(git_repository)?;
+        let args = fetch_args(
+            repository,
+            &outcome.name,
+            refspecs,
+            git_repository.is_shallow(),
+        );
+        let result = git(repository, git_directory(git_repository), args);
+        if let Ok(output) = &result {
+            outcome.transfer = transfer_stats(&String::from_utf8_lossy(&output.stderr));
+        }
+        let after = fetch::ref_targets(git_repository)?;
+        outcome.refs = fetch::ref_updates(git_repository, &before, &after);
+        result.map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_fetch_args() {
+        let repository = GitRepository {
+            depth: Some(1),
+            filter: Some("blob:none".to_string()),
+            prune: true,
+            tags: Some(TagPolicy::None),
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch_args(&repository, "origin", &["main".to_string()], false),
+            vec![
+                "fetch",
+                "--progress",
+                "--depth=1",
+                "--filter=blob:none",
+                "--prune",
+                "--no-tags",
+                "--",
+                "origin",
+                "main"
+            ]
+        );
+        let repository = GitRepository {
+            unshallow: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch_args(&repository, "origin", &[], true),
+            vec!["fetch", "--progress", "--unshallow", "--", "origin"]
+        );
+        assert_eq!(
+            fetch_args(&repository, "origin", &[], false),
+            vec!["fetch", "--progress", "--", "origin"]
+        );
+    }
+
+    #[test]
+    fn test_transfer_stats() {
+        let stderr = "Receiving objects:  91% (11/12)\rReceiving objects: 100% (12/12)\rReceiving objects: 100% (12/12), 59.34 KiB | 59.34 MiB/s, done.\nResolving deltas: 100% (2/2), done.\n";
+        assert_eq!(
+            transfer_stats(stderr),
+            TransferStats {
+                objects: 12,
+                bytes: 60764
+            }
+        );
+        assert_eq!(
+            transfer_stats("Receiving objects: 100% (3/3), done.\n"),
+            TransferStats {
+                objects: 3,
+                bytes: 0
+            }
+        );
+        assert_eq!(transfer_stats(""), TransferStats::default());
+    }
+
+    #[test]
+    fn test_parse_ls_remote() {
+        let refs = parse_ls_remote(
+            "481b9d4c63af1e43b61206316bcabf3344d55cc1\tHEAD\n481b9d4c63af1e43b61206316bcabf3344d55cc1\trefs/heads/master\n",
+        )
+        .unwrap();
+        assert_eq!(refs.len(), 2);
+        assert_eq!(refs[1].0, "refs/heads/master");
+        assert!(parse_ls_remote("garbage\n").is_err());
+    }
+
+    #[test]
+    fn test_credential_args() {
+        let ssh_command = |credentials| {
+            let mut command = Command::new("git");
+            credential_args(Some(&credentials), &mut command).unwrap();
+            command
+                .get_envs()
+                .find(|(name, _)| *name == "GIT_SSH_COMMAND")
+                .and_then(|(_, value)| value)
+                .map(|value| value.to_string_lossy().into_owned())
+        };
+        assert_eq!(
+            ssh_command(Credentials::SshKey {
+                username: Some("o'neil".to_string()),
+                private_key: "/keys/it's".into(),
+                public_key: None,
+                passphrase_file: None,
+            }),
+            Some("ssh -o IdentitiesOnly=yes -i '/keys/it'\\''s' -l 'o'\\''neil'".to_string())
+        );
+
+        std::env::set_var("GIT_AUTO_FETCH_TEST_CLI_TOKEN", "secret");
+        let mut command = Command::new("git");
+        credential_args(
+            Some(&Credentials::Token {
+                env: "GIT_AUTO_FETCH_TEST_CLI_TOKEN".to_string(),
+                username: Some("$(touch pwned)".to_string()),
+            }),
+            &mut command,
+        )
+        .unwrap();
+        let args: Vec<_> = command.get_args().collect();
+        assert!(!args
+            .iter()
+            .any(|arg| arg.to_string_lossy().contains("pwned")));
+        assert!(command.get_envs().any(|(name, value)| {
+            name == TOKEN_USERNAME_VARIABLE && value == Some(OsStr::new("$(touch pwned)"))
+        }));
+    }
+
+    #[test]
+    fn test_error_from_stderr() {
+        let error = error_from_stderr(
+            "fatal: unable to access 'https://example.com/': Could not resolve host: example.com\n",
+        );
+        assert_eq!(error.class(), ErrorClass::Net);
+        assert!(error.message().starts_with("fatal: unable to access"));
+        let error = error_from_stderr("remote: nope\nfatal: Authentication failed for 'x'\n");
+        assert_eq!(error.code(), ErrorCode::Auth);
+        let error = error_from_stderr(
+            "fatal: unable to access 'https://example.com/': The requested URL returned error: 403\n",
+        );
+        assert_eq!(error.code(), ErrorCode::Auth);
+        assert!(!retry::is_transient(&error));
+        let error = error_from_stderr(
+            "fatal: unable to access 'https://example.com/': The requested URL returned error: 503\n",
+        );
+        assert!(retry::is_transient(&error));
+        assert_eq!(error_from_stderr("").message(), "git failed");
+    }
+}
@@ -0,0 +1,113 @@
+use super::FetchBackend;
+use crate::{
+    auth,
+    fetch::{FetchOutcome, RefChange, RefUpdate, RemoteOutcome},
+    progress::ProgressDisplay,
+    GitRepository,
+};
+use git2::Oid;
+use std::cell::RefCell;
+
+/// Tag option for `repository`; without a policy the remote's `tagOpt`
+/// setting applies.
+fn autotag(repository: &GitRepository) -> git2::AutotagOption {
+    repository
+        .tags
+        .map(git2::AutotagOption::from)
+        .unwrap_or(git2::AutotagOption::Unspecified)
+}
+
+/// Identifies a transfer in progress output.
+fn transfer_label(repository: &GitRepository, remote: &str) -> String {
+    format!("{} ({})", repository.local_path.display(), remote)
+}
+
+/// Fetches through libgit2, reporting progress as it goes.
+pub struct Libgit2;
+
+impl FetchBackend for Libgit2 {
+    fn clone(
+        &self,
+        repository: &GitRepository,
+        url: &str,
+        remote: &str,
+        progress: &ProgressDisplay,
+        outcome: &mut FetchOutcome,
+    ) -> Result<(), git2::Error> {
+        let label = transfer_label(repository, remote);
+        let mut callbacks = auth::remote_callbacks(
+            repository.credentials.as_ref(),
+            git2::Config::open_default()?,
+        );
+        progress.watch(&mut callbacks, &label);
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options
+            .remote_callbacks(callbacks)
+            .download_tags(autotag(repository));
+        let result = git2::build::RepoBuilder::new()
+            .remote_create(move |git_repository, _, url| git_repository.remote(remote, url))
+            .fetch_options(fetch_options)
+            .clone(url, &repository.local_path);
+        outcome.clone_transfer = progress.finish(&label);
+        result.map(drop)
+    }
+
+    fn list(
+        &self,
+        git_repository: &git2::Repository,
+        repository: &GitRepository,
+        remote: &str,
+    ) -> Result<Vec<(String, Oid)>, git2::Error> {
+        let mut remote = git_repository.find_remote(remote)?;
+        let callbacks =
+            auth::remote_callbacks(repository.credentials.as_ref(), git_repository.config()?);
+        let connection = remote.connect_auth(git2::Direction::Fetch, Some(callbacks), None)?;
+        let advertised = connection
+            .list()?
+            .iter()
+            .map(|head| (head.name().to_string(), head.oid()))
+            .collect();
+        Ok(advertised)
+    }
+
+    fn fetch(
+        &self,
+        git_repository: &git2::Repository,
+        repository: &GitRepository,
+        refspecs: &[String],
+        progress: &ProgressDisplay,
+        outcome: &mut RemoteOutcome,
+    ) -> Result<(), git2::Error> {
+        let mut remote = git_repository.find_remote(&outcome.name)?;
+        let label = transfer_label(repository, &outcome.name);
+        let refs = RefCell::new(Vec::new());
+        let mut callbacks =
+            auth::remote_callbacks(repository.credentials.as_ref(), git_repository.config()?);
+        progress.watch(&mut callbacks, &label);
+        callbacks.update_tips(|refname, old, new| {
+            refs.borrow_mut().push((refname.to_string(), old, new));
+            true
+        });
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options
+            .remote_callbacks(callbacks)
+            .download_tags(autotag(repository));
+        if repository.prune || repository.prune_tags {
+            fetch_options.prune(git2::FetchPrune::On);
+        }
+        let result = remote.fetch(refspecs, Some(&mut fetch_options), None);
+        drop(fetch_options);
+        outcome.transfer = progress.finish(&label);
+        outcome.refs = refs
+            .into_inner()
+            .into_iter()
+            .map(|(name, old, new)| RefUpdate {
+                name,
+                old,
+                new,
+                change: RefChange::classify(git_repository, old, new),
+            })
+            .collect();
+        result
+    }
+}
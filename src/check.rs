@@ -0,0 +1,456 @@
+use crate::{
+    auth::Credentials,
+    backend::Backend,
+    fetch::{self, ALL_REMOTES},
+    retry::RetryPolicy,
+    scan, Config, GitRepository, Source,
+};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// Something wrong with the config, and where in which file it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// The config file the problem is in, if it is in a single one.
+    pub file: Option<PathBuf>,
+    pub location: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// Checks a single side of a refspec, where `*` may appear once.
+fn validate_ref_pattern(pattern: &str) -> Result<(), String> {
+    if pattern.matches('*').count() > 1 {
+        return Err(format!("{:?} contains more than one `*`", pattern));
+    }
+    let name = pattern.replace('*', "x");
+    let name = if name.starts_with("refs/") || name == "HEAD" {
+        name
+    } else {
+        format!("refs/heads/{}", name)
+    };
+    if git2::Reference::is_valid_name(&name) {
+        Ok(())
+    } else {
+        Err(format!("{:?} is not a valid ref name", pattern))
+    }
+}
+
+/// Checks that `refspec` is a valid fetch refspec such as `main` or
+/// `+refs/heads/*:refs/remotes/origin/*`.
+pub fn validate_refspec(refspec: &str) -> Result<(), String> {
+    let spec = refspec.strip_prefix('+').unwrap_or(refspec);
+    let (src, dst) = match spec.split_once(':') {
+        Some((src, dst)) => (src, Some(dst)),
+        None => (spec, None),
+    };
+    if src.is_empty() {
+        return Err(format!("{:?} has no source", refspec));
+    }
+    validate_ref_pattern(src)?;
+    if let Some(dst) = dst.filter(|dst| !dst.is_empty()) {
+        validate_ref_pattern(dst)?;
+        if src.contains('*') != dst.contains('*') {
+            return Err(format!(
+                "{:?} must use `*` on both sides or on neither",
+                refspec
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `filter` is a partial clone filter git understands, such as
+/// `blob:none`, `blob:limit=1m` or `tree:0`.
+pub fn validate_filter(filter: &str) -> Result<(), String> {
+    let valid = match filter.split_once(':') {
+        Some(("blob", "none")) => true,
+        Some(("blob", limit)) => limit.strip_prefix("limit=").is_some_and(|size| {
+            let digits = size.trim_end_matches(['k', 'm', 'g']);
+            !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+        }),
+        Some(("tree", depth)) => depth.parse::<u32>().is_ok(),
+        Some(("object", kind)) => {
+            matches!(kind, "type=blob" | "type=tree" | "type=commit" | "type=tag")
+        }
+        Some(("sparse", oid)) => oid.starts_with("oid="),
+        Some(("combine", filters)) => !filters.is_empty(),
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{:?} is not a partial clone filter", filter))
+    }
+}
+
+/// Checks that `name` is a portable environment variable name, i.e.
+/// matches `[A-Za-z_][A-Za-z0-9_]*`.
+pub fn validate_env_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("{:?} is not an environment variable name", name))
+    }
+}
+
+/// Where in the config files a setting is written.
+#[derive(Clone)]
+struct Location<'a> {
+    file: Option<&'a Path>,
+    path: String,
+}
+
+impl<'a> Location<'a> {
+    /// The location of an entry, in its own file if it was read from one.
+    fn of_entry(source: Option<&'a Source>, list: &str, index: usize) -> Self {
+        Location {
+            file: source.map(|source| source.file.as_path()),
+            path: format!("{}[{}]", list, source.map_or(index, |source| source.index)),
+        }
+    }
+
+    /// The location of `field` below this one; `field` starts with `.` or
+    /// `[`.
+    fn join(&self, field: &str) -> Self {
+        Location {
+            file: self.file,
+            path: format!("{}{}", self.path, field),
+        }
+    }
+
+    fn problem(&self, message: String) -> Problem {
+        Problem {
+            file: self.file.map(Path::to_path_buf),
+            location: self.path.clone(),
+            message,
+        }
+    }
+}
+
+/// Checks the numbers of a retry policy, which `RetryPolicy::run` can't
+/// make sense of otherwise.
+fn check_retry(location: &Location, retry: &Option<RetryPolicy>, problems: &mut Vec<Problem>) {
+    let retry = match retry {
+        Some(retry) => retry,
+        None => return,
+    };
+    if !retry.backoff_factor.is_finite() || retry.backoff_factor < 1.0 {
+        problems.push(location.join(".backoff_factor").problem(format!(
+            "{} is not a number of at least 1",
+            retry.backoff_factor
+        )));
+    }
+    if !(0.0..=1.0).contains(&retry.jitter) {
+        problems.push(
+            location
+                .join(".jitter")
+                .problem(format!("{} is not between 0 and 1", retry.jitter)),
+        );
+    }
+}
+
+fn check_refspecs(
+    location: &Location,
+    refspecs: &Option<Vec<String>>,
+    problems: &mut Vec<Problem>,
+) {
+    for (index, refspec) in refspecs.iter().flatten().enumerate() {
+        if let Err(message) = validate_refspec(refspec) {
+            problems.push(location.join(&format!("[{}]", index)).problem(message));
+        }
+    }
+}
+
+/// Checks the settings of one repository entry that can be judged without
+/// looking at the file system. `default_backend` is the backend of the config
+/// the entry is in, for entries that don't choose one.
+fn check_repository_settings(
+    location: &Location,
+    repository: &GitRepository,
+    default_backend: Option<Backend>,
+    problems: &mut Vec<Problem>,
+) {
+    check_retry(&location.join(".retry"), &repository.retry, problems);
+    if let Some(Credentials::Token { env, .. }) = &repository.credentials {
+        if let Err(message) = validate_env_name(env) {
+            problems.push(location.join(".credentials.env").problem(message));
+        }
+    }
+    if repository.depth == Some(0) {
+        problems.push(
+            location
+                .join(".depth")
+                .problem("must be at least 1".to_string()),
+        );
+    }
+    if repository.depth.is_some() && repository.unshallow {
+        problems.push(
+            location
+                .join(".unshallow")
+                .problem("cannot be combined with depth".to_string()),
+        );
+    }
+    if let Some(filter) = &repository.filter {
+        if let Err(message) = validate_filter(filter) {
+            problems.push(location.join(".filter").problem(message));
+        }
+    }
+    if repository.backend == Some(Backend::Libgit2)
+        && (repository.depth.is_some() || repository.unshallow || repository.filter.is_some())
+    {
+        problems.push(location.join(".backend").problem(
+            "libgit2 cannot do shallow or partial fetches, depth, unshallow and filter need git"
+                .to_string(),
+        ));
+    }
+    if let Some(Credentials::SshKey {
+        passphrase_file: Some(_),
+        ..
+    }) = &repository.credentials
+    {
+        let backend = Backend::select(&GitRepository {
+            backend: repository.backend.or(default_backend),
+            ..repository.clone()
+        });
+        if backend == Backend::Git {
+            problems.push(
+                location.join(".credentials.passphrase_file").problem(
+                    "the git executable, used for this entry, cannot read a passphrase_file"
+                        .to_string(),
+                ),
+            );
+        }
+    }
+    check_refspecs(
+        &location.join(".fetch_branches"),
+        &repository.fetch_branches,
+        problems,
+    );
+    for (index, remote) in repository.remotes.iter().enumerate() {
+        check_refspecs(
+            &location.join(&format!(".remotes[{}].fetch_branches", index)),
+            &remote.fetch_branches,
+            problems,
+        );
+    }
+}
+
+/// Checks that the repository entry, with the config-level defaults filled
+/// in, names a repository and remotes that exist.
+fn check_repository(location: &Location, repository: &GitRepository, problems: &mut Vec<Problem>) {
+    let mut problem = |field: &str, message: String| {
+        problems.push(location.join(field).problem(message));
+    };
+    let git_repository = match git2::Repository::open(&repository.local_path) {
+        Ok(git_repository) => git_repository,
+        Err(_) if repository.url.is_some() && !repository.local_path.exists() => return,
+        Err(error) => {
+            problem(
+                ".local_path",
+                format!(
+                    "{:?} is not a git repository: {}",
+                    repository.local_path,
+                    error.message()
+                ),
+            );
+            return;
+        }
+    };
+    if repository.remotes.is_empty() {
+        let found = match &repository.remote {
+            Some(remote) => git_repository.find_remote(remote).map(|_| ()),
+            None => fetch::default_remote(&git_repository).map(|_| ()),
+        };
+        if let Err(error) = found {
+            problem(".remote", error.message().to_string());
+        }
+    }
+    for (index, remote) in repository.remotes.iter().enumerate() {
+        if remote.name != ALL_REMOTES {
+            if let Err(error) = git_repository.find_remote(&remote.name) {
+                problem(
+                    &format!(".remotes[{}].name", index),
+                    error.message().to_string(),
+                );
+            }
+        }
+    }
+}
+
+/// Checks the settings of the config file `file`, which can be judged
+/// without looking at the file system or at other files. A config with such
+/// problems is not used at all.
+pub fn check_settings(config: &Config, file: &Path) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let top = |path: &str| Location {
+        file: Some(file),
+        path: path.to_string(),
+    };
+    check_retry(&top("retry"), &config.retry, &mut problems);
+    check_refspecs(
+        &top("fetch_branches"),
+        &config.fetch_branches,
+        &mut problems,
+    );
+    for (index, repository) in config.repositories.iter().enumerate() {
+        check_repository_settings(
+            &top(&format!("repositories[{}]", index)),
+            repository,
+            config.backend,
+            &mut problems,
+        );
+    }
+    problems
+}
+
+/// Validates `config` against the file system without fetching anything.
+/// The problems `check_settings` found while loading come first.
+pub fn check_config(config: &Config) -> Vec<Problem> {
+    let mut problems = config.problems.clone();
+    for (index, repository) in config.repositories.iter().enumerate() {
+        let mut repository = repository.clone();
+        repository.inherit(config);
+        let location = Location::of_entry(repository.source.as_ref(), "repositories", index);
+        check_repository(&location, &repository, &mut problems);
+    }
+    let mut known = config.repositories.clone();
+    for (index, scan_root) in config.scan_roots.iter().enumerate() {
+        let location = Location::of_entry(scan_root.source.as_ref(), "scan_roots", index);
+        let discovered = match scan_root.discover() {
+            Ok(discovered) => discovered,
+            Err(error) => {
+                problems.push(location.problem(format!("{:#}", error)));
+                continue;
+            }
+        };
+        let before = known.len();
+        scan::merge(&mut known, discovered);
+        for repository in &mut known[before..] {
+            repository.inherit(config);
+            let location = location.join(&format!(" ({})", repository.local_path.display()));
+            check_repository_settings(&location, repository, None, &mut problems);
+            check_repository(&location, repository, &mut problems);
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_refspec() {
+        for refspec in &[
+            "main",
+            "feature/x",
+            "+refs/heads/*:refs/remotes/origin/*",
+            "refs/tags/v1:refs/tags/v1",
+            "HEAD",
+        ] {
+            assert_eq!(validate_refspec(refspec), Ok(()), "{}", refspec);
+        }
+        for refspec in &[
+            "",
+            ":refs/heads/main",
+            "refs/heads/*:refs/remotes/origin/main",
+            "refs/heads/*/*:refs/remotes/*/*",
+            "bad..name",
+            "with space",
+        ] {
+            assert!(validate_refspec(refspec).is_err(), "{}", refspec);
+        }
+    }
+
+    #[test]
+    fn test_validate_env_name() {
+        for name in &["GITHUB_TOKEN", "_token", "t0ken"] {
+            assert_eq!(validate_env_name(name), Ok(()), "{}", name);
+        }
+        for name in &["", "0TOKEN", "TOKEN}; rm -rf ~; {", "A-B"] {
+            assert!(validate_env_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_check_settings() {
+        let mut config = Config {
+            retry: Some(RetryPolicy {
+                backoff_factor: 0.5,
+                ..Default::default()
+            }),
+            repositories: vec![GitRepository {
+                retry: Some(RetryPolicy {
+                    backoff_factor: f64::INFINITY,
+                    jitter: 2.0,
+                    ..Default::default()
+                }),
+                credentials: Some(Credentials::Token {
+                    env: "$(id)".to_string(),
+                    username: None,
+                }),
+                depth: Some(1),
+                backend: Some(Backend::Libgit2),
+                ..Default::default()
+            }],
+            backend: Some(Backend::Git),
+            ..Default::default()
+        };
+        let ssh_key = Credentials::SshKey {
+            username: None,
+            private_key: PathBuf::from("id_ed25519"),
+            public_key: None,
+            passphrase_file: Some(PathBuf::from("passphrase")),
+        };
+        config.repositories.push(GitRepository {
+            credentials: Some(ssh_key.clone()),
+            ..Default::default()
+        });
+        config.repositories.push(GitRepository {
+            credentials: Some(ssh_key),
+            backend: Some(Backend::Libgit2),
+            ..Default::default()
+        });
+        let locations: Vec<String> = check_settings(&config, Path::new("config.toml"))
+            .into_iter()
+            .map(|problem| format!("{}: {}", problem.file.unwrap().display(), problem.location))
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                "config.toml: retry.backoff_factor",
+                "config.toml: repositories[0].retry.backoff_factor",
+                "config.toml: repositories[0].retry.jitter",
+                "config.toml: repositories[0].credentials.env",
+                "config.toml: repositories[0].backend",
+                "config.toml: repositories[1].credentials.passphrase_file",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_filter() {
+        for filter in &["blob:none", "blob:limit=1m", "tree:0", "object:type=blob"] {
+            assert_eq!(validate_filter(filter), Ok(()), "{}", filter);
+        }
+        for filter in &["", "blob", "blob:limit=", "tree:x", "none"] {
+            assert!(validate_filter(filter).is_err(), "{}", filter);
+        }
+    }
+}
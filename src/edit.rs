@@ -0,0 +1,536 @@
+use crate::canonical_path;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+/// A repository entry added from the command line.
+#[derive(Serialize, Debug, Default)]
+pub struct NewRepository {
+    pub local_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fetch_branches: Vec<String>,
+}
+
+/// An edit asked for on the command line that doesn't fit the config file,
+/// such as removing a repository that isn't in it.
+#[derive(Debug)]
+pub struct UsageError(String);
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+fn same_path(a: &Path, b: &Path) -> bool {
+    a == b || canonical_path(a) == canonical_path(b)
+}
+
+/// Indentation of the first indented line of `text`, used to write JSON the
+/// way it was read.
+fn indentation(text: &str) -> String {
+    text.lines()
+        .map(|line| &line[..line.len() - line.trim_start().len()])
+        .find(|indent| !indent.is_empty())
+        .unwrap_or("  ")
+        .to_string()
+}
+
+/// Offset of the first non-whitespace character at or after `offset`.
+fn skip_whitespace(text: &str, offset: usize) -> usize {
+    text.len() - text[offset..].trim_start().len()
+}
+
+/// Offset just past the JSON value starting at `start` in valid JSON `text`.
+fn value_end(text: &str, start: usize) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (offset, byte) in text.bytes().enumerate().skip(start) {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
+                if depth == 0 {
+                    return offset + 1;
+                }
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' if depth == 0 => return offset,
+            b'}' | b']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return offset + 1;
+                }
+            }
+            b',' if depth == 0 => return offset,
+            byte if depth == 0 && byte.is_ascii_whitespace() => return offset,
+            _ => (),
+        }
+    }
+    text.len()
+}
+
+/// A member of a JSON object or an element of a JSON array, as offsets into
+/// the text it was found in.
+struct Item {
+    /// Where the item starts: its key in an object, its value in an array.
+    start: usize,
+    key: Option<String>,
+    value: (usize, usize),
+}
+
+/// A JSON object or array, as offsets into the text it was found in.
+struct Container {
+    start: usize,
+    items: Vec<Item>,
+    /// Offset of the closing bracket.
+    end: usize,
+}
+
+impl Container {
+    /// Finds the items of the object or array starting at `start` in valid
+    /// JSON `text`.
+    fn parse(text: &str, start: usize) -> Self {
+        let is_object = text.as_bytes()[start] == b'{';
+        let mut items = Vec::new();
+        let mut offset = skip_whitespace(text, start + 1);
+        while !matches!(text.as_bytes()[offset], b'}' | b']') {
+            let item_start = offset;
+            let key = if is_object {
+                let key_end = value_end(text, offset);
+                let key = serde_json::from_str(&text[offset..key_end]).ok();
+                // Skip the `:` after the key.
+                offset = skip_whitespace(text, skip_whitespace(text, key_end) + 1);
+                key
+            } else {
+                None
+            };
+            let end = value_end(text, offset);
+            items.push(Item {
+                start: item_start,
+                key,
+                value: (offset, end),
+            });
+            offset = skip_whitespace(text, end);
+            if text.as_bytes()[offset] == b',' {
+                offset = skip_whitespace(text, offset + 1);
+            }
+        }
+        Container {
+            start,
+            items,
+            end: offset,
+        }
+    }
+
+    /// The text between the items, taken from the text itself where
+    /// possible.
+    fn separator<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.items.as_slice() {
+            [first, second, ..] => Cow::Borrowed(&text[first.value.1..second.start]),
+            [first] => Cow::Owned(format!(",{}", &text[self.start + 1..first.start])),
+            [] => Cow::Borrowed(","),
+        }
+    }
+
+    /// `text` with the items of this container replaced by `items`, keeping
+    /// the whitespace around them.
+    fn replace_items(&self, text: &str, items: &[&str]) -> String {
+        let (inner_start, inner_end) = match (self.items.first(), self.items.last()) {
+            (Some(first), Some(last)) => (first.start, last.value.1),
+            _ => (self.start + 1, self.end),
+        };
+        let inner = if items.is_empty() {
+            (self.start + 1, self.end)
+        } else {
+            (inner_start, inner_end)
+        };
+        format!(
+            "{}{}{}",
+            &text[..inner.0],
+            items.join(&self.separator(text)),
+            &text[inner.1..]
+        )
+    }
+}
+
+/// Indentation of the line `offset` is on.
+fn line_indentation(text: &str, offset: usize) -> &str {
+    let line_start = text[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+    let line = &text[line_start..];
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// `value` as pretty JSON indented by `unit`, starting at a line indented by
+/// `indent`.
+fn pretty_json(value: &impl Serialize, unit: &str, indent: &str) -> Result<String> {
+    let mut output = Vec::new();
+    let formatter = serde_json::ser::PrettyFormatter::with_indent(unit.as_bytes());
+    let mut serializer = serde_json::Serializer::with_formatter(&mut output, formatter);
+    value.serialize(&mut serializer)?;
+    Ok(String::from_utf8(output)?.replace('\n', &format!("\n{}", indent)))
+}
+
+/// The top-level object of the JSON config `text` and its `repositories`
+/// array, if it has one.
+fn json_repositories(text: &str) -> Result<(Container, Option<Container>)> {
+    let document: serde_json::Value = serde_json::from_str(text)?;
+    if !document.is_object() {
+        bail!("the config is not a JSON object");
+    }
+    if matches!(document.get("repositories"), Some(repositories) if !repositories.is_array()) {
+        bail!("`repositories` is not an array");
+    }
+    let top = Container::parse(text, skip_whitespace(text, 0));
+    let repositories = top
+        .items
+        .iter()
+        .find(|item| item.key.as_deref() == Some("repositories"))
+        .map(|item| Container::parse(text, item.value.0));
+    Ok((top, repositories))
+}
+
+fn json_path(text: &str, item: &Item) -> Option<PathBuf> {
+    let entry: serde_json::Value = serde_json::from_str(&text[item.value.0..item.value.1]).ok()?;
+    entry.get("local_path")?.as_str().map(PathBuf::from)
+}
+
+/// `text` with an entry for `repository` appended to `repositories`, in the
+/// layout the other entries use. The rest of the text is left as it is.
+fn json_add(text: &str, repository: &NewRepository) -> Result<String> {
+    let (top, repositories) = json_repositories(text)?;
+    let unit = indentation(text);
+    let repositories = match repositories {
+        Some(repositories) => repositories,
+        None => {
+            let member_indent = match top.items.first() {
+                Some(first) => line_indentation(text, first.start).to_string(),
+                None => unit.clone(),
+            };
+            let member = format!(
+                "\"repositories\": {}",
+                pretty_json(&[repository], &unit, &member_indent)?
+            );
+            let mut members: Vec<&str> = top
+                .items
+                .iter()
+                .map(|item| &text[item.start..item.value.1])
+                .collect();
+            members.push(&member);
+            return Ok(if top.items.is_empty() {
+                format!(
+                    "{}\n{}{}\n{}",
+                    &text[..top.start + 1],
+                    member_indent,
+                    member,
+                    &text[top.end..]
+                )
+            } else {
+                top.replace_items(text, &members)
+            });
+        }
+    };
+    if repositories.items.iter().any(|item| {
+        json_path(text, item).is_some_and(|path| same_path(&path, &repository.local_path))
+    }) {
+        return Err(
+            UsageError(format!("{:?} is already configured", repository.local_path)).into(),
+        );
+    }
+    let entry = match repositories.items.first() {
+        Some(first) if text[repositories.start..first.start].contains('\n') => {
+            pretty_json(repository, &unit, line_indentation(text, first.start))?
+        }
+        Some(_) => serde_json::to_string(repository)?,
+        None => {
+            let indent = line_indentation(text, repositories.start);
+            let entry = pretty_json(repository, &unit, &format!("{}{}", indent, unit))?;
+            return Ok(format!(
+                "{}\n{}{}{}\n{}{}",
+                &text[..repositories.start + 1],
+                indent,
+                unit,
+                entry,
+                indent,
+                &text[repositories.end..]
+            ));
+        }
+    };
+    let mut entries: Vec<&str> = repositories
+        .items
+        .iter()
+        .map(|item| &text[item.start..item.value.1])
+        .collect();
+    entries.push(&entry);
+    Ok(repositories.replace_items(text, &entries))
+}
+
+/// `text` without the entries of `repositories` for `local_path`.
+fn json_remove(text: &str, local_path: &Path) -> Result<String> {
+    let (_, repositories) = json_repositories(text)?;
+    let repositories = match repositories {
+        Some(repositories) => repositories,
+        None => return Err(not_configured(local_path)),
+    };
+    let kept: Vec<&str> = repositories
+        .items
+        .iter()
+        .filter(|item| !json_path(text, item).is_some_and(|path| same_path(&path, local_path)))
+        .map(|item| &text[item.start..item.value.1])
+        .collect();
+    if kept.len() == repositories.items.len() {
+        return Err(not_configured(local_path));
+    }
+    Ok(repositories.replace_items(text, &kept))
+}
+
+fn not_configured(local_path: &Path) -> anyhow::Error {
+    UsageError(format!("{:?} is not configured", local_path)).into()
+}
+
+fn toml_table(repository: &NewRepository) -> Result<toml_edit::Table> {
+    let mut table = toml_edit::Table::new();
+    let path = match repository.local_path.to_str() {
+        Some(path) => path,
+        None => bail!("{:?} is not valid UTF-8", repository.local_path),
+    };
+    table["local_path"] = toml_edit::value(path);
+    if let Some(remote) = &repository.remote {
+        table["remote"] = toml_edit::value(remote.as_str());
+    }
+    if let Some(url) = &repository.url {
+        table["url"] = toml_edit::value(url.as_str());
+    }
+    if !repository.fetch_branches.is_empty() {
+        let branches: toml_edit::Array = repository.fetch_branches.iter().collect();
+        table["fetch_branches"] = toml_edit::value(branches);
+    }
+    Ok(table)
+}
+
+fn toml_path(entry: &toml_edit::Table) -> Option<&Path> {
+    entry.get("local_path")?.as_str().map(Path::new)
+}
+
+/// Contents of `config_file` with `edit_toml` or `edit_json` applied,
+/// depending on the file's format. Comments and layout survive in TOML
+/// files; JSON edits only touch the `repositories` array.
+fn edit(
+    config_file: &Path,
+    edit_toml: impl FnOnce(&mut toml_edit::ArrayOfTables) -> Result<()>,
+    edit_json: impl FnOnce(&str) -> Result<String>,
+) -> Result<String> {
+    let text = fs::read_to_string(config_file)
+        .with_context(|| format!("cannot read {:?}", config_file))?;
+    match config_file
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("toml") => {
+            let mut document: toml_edit::DocumentMut = text.parse()?;
+            let repositories = document
+                .entry("repositories")
+                .or_insert_with(|| toml_edit::Item::ArrayOfTables(Default::default()));
+            match repositories.as_array_of_tables_mut() {
+                Some(repositories) => edit_toml(repositories)?,
+                None => bail!("`repositories` must be written as [[repositories]] tables"),
+            }
+            Ok(document.to_string())
+        }
+        Some("json") => edit_json(&text),
+        _ => Err(UsageError(format!(
+            "cannot edit {:?}, only TOML and JSON config files are supported",
+            config_file
+        ))
+        .into()),
+    }
+}
+
+/// Contents of the config file with `repository` appended.
+pub fn add_repository(config_file: &Path, repository: &NewRepository) -> Result<String> {
+    edit(
+        config_file,
+        |repositories| {
+            if repositories.iter().any(|entry| {
+                toml_path(entry).is_some_and(|path| same_path(path, &repository.local_path))
+            }) {
+                return Err(UsageError(format!(
+                    "{:?} is already configured",
+                    repository.local_path
+                ))
+                .into());
+            }
+            repositories.push(toml_table(repository)?);
+            Ok(())
+        },
+        |text| json_add(text, repository),
+    )
+}
+
+/// Contents of the config file without the entries for `local_path`.
+pub fn remove_repository(config_file: &Path, local_path: &Path) -> Result<String> {
+    edit(
+        config_file,
+        |repositories| {
+            let before = repositories.len();
+            repositories
+                .retain(|entry| !toml_path(entry).is_some_and(|path| same_path(path, local_path)));
+            if repositories.len() == before {
+                return Err(not_configured(local_path));
+            }
+            Ok(())
+        },
+        |text| json_remove(text, local_path),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    fn add(config_file: &Path, repository: &NewRepository) -> Result<()> {
+        fs::write(config_file, add_repository(config_file, repository)?)?;
+        Ok(())
+    }
+
+    fn remove(config_file: &Path, local_path: &Path) -> Result<()> {
+        fs::write(config_file, remove_repository(config_file, local_path)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_toml_keeps_comments() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let config_file = temp.child("config.toml");
+        config_file
+            .write_str(
+                "# shared settings\nfetch_interval = 300\n\n[[repositories]]\n# the main app\nlocal_path = \"/repos/app\"\n",
+            )
+            .unwrap();
+        add(
+            config_file.path(),
+            &NewRepository {
+                local_path: PathBuf::from("/repos/lib"),
+                remote: Some("upstream".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let text = fs::read_to_string(config_file.path()).unwrap();
+        assert!(text.starts_with("# shared settings\nfetch_interval = 300\n"));
+        assert!(text.contains("# the main app\nlocal_path = \"/repos/app\""));
+        assert!(text.contains("local_path = \"/repos/lib\"\nremote = \"upstream\""));
+        assert!(add(
+            config_file.path(),
+            &NewRepository {
+                local_path: PathBuf::from("/repos/lib"),
+                ..Default::default()
+            }
+        )
+        .is_err());
+
+        remove(config_file.path(), Path::new("/repos/app")).unwrap();
+        let text = fs::read_to_string(config_file.path()).unwrap();
+        assert!(!text.contains("/repos/app"));
+        assert!(text.contains("/repos/lib"));
+        assert!(remove(config_file.path(), Path::new("/repos/app")).is_err());
+    }
+
+    #[test]
+    fn test_edit_json_keeps_order_and_indent() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let config_file = temp.child("config.json");
+        config_file
+            .write_str("{\n    \"repositories\": [],\n    \"fetch_interval\": 60\n}\n")
+            .unwrap();
+        add(
+            config_file.path(),
+            &NewRepository {
+                local_path: PathBuf::from("/repos/lib"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let text = fs::read_to_string(config_file.path()).unwrap();
+        assert_eq!(
+            text,
+            "{\n    \"repositories\": [\n        {\n            \"local_path\": \"/repos/lib\"\n        }\n    ],\n    \"fetch_interval\": 60\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_edit_json_keeps_layout() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let config_file = temp.child("config.json");
+        config_file
+            .write_str(
+                "{\"fetch_interval\":60,  \"repositories\": [ {\"local_path\": \"/repos/app\", \"remote\": \"upstream\"} ] }",
+            )
+            .unwrap();
+        add(
+            config_file.path(),
+            &NewRepository {
+                local_path: PathBuf::from("/repos/lib"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let text = fs::read_to_string(config_file.path()).unwrap();
+        assert_eq!(
+            text,
+            "{\"fetch_interval\":60,  \"repositories\": [ {\"local_path\": \"/repos/app\", \"remote\": \"upstream\"}, {\"local_path\":\"/repos/lib\"} ] }"
+        );
+        let error = add(
+            config_file.path(),
+            &NewRepository {
+                local_path: PathBuf::from("/repos/lib"),
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(error.is::<UsageError>());
+
+        remove(config_file.path(), Path::new("/repos/app")).unwrap();
+        remove(config_file.path(), Path::new("/repos/lib")).unwrap();
+        let text = fs::read_to_string(config_file.path()).unwrap();
+        assert_eq!(text, "{\"fetch_interval\":60,  \"repositories\": [] }");
+        let error = remove(config_file.path(), Path::new("/repos/lib")).unwrap_err();
+        assert!(error.is::<UsageError>());
+
+        config_file
+            .write_str("{\n  \"fetch_interval\": 60\n}\n")
+            .unwrap();
+        add(
+            config_file.path(),
+            &NewRepository {
+                local_path: PathBuf::from("/repos/lib"),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let text = fs::read_to_string(config_file.path()).unwrap();
+        assert_eq!(
+            text,
+            "{\n  \"fetch_interval\": 60,\n  \"repositories\": [\n    {\n      \"local_path\": \"/repos/lib\"\n    }\n  ]\n}\n"
+        );
+    }
+}
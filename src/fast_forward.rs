@@ -0,0 +1,184 @@
+use git2::{build::CheckoutBuilder, Branch, BranchType, Oid, Repository, StatusOptions};
+use log::debug;
+use std::fmt;
+
+/// What happened to a local branch when trying to fast-forward it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BranchUpdate {
+    Updated {
+        from: Oid,
+        to: Oid,
+    },
+    UpToDate,
+    /// The branch has commits its upstream doesn't, and lacks none of the
+    /// upstream's.
+    Ahead,
+    /// The branch and its upstream both have commits the other doesn't, so
+    /// it can't be moved without a merge.
+    Diverged,
+    Skipped(String),
+}
+
+impl fmt::Display for BranchUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BranchUpdate::Updated { from, to } => write!(f, "updated {}..{}", from, to),
+            BranchUpdate::UpToDate => write!(f, "up to date"),
+            BranchUpdate::Ahead => write!(f, "ahead of upstream"),
+            BranchUpdate::Diverged => write!(f, "diverged"),
+            BranchUpdate::Skipped(reason) => write!(f, "skipped ({})", reason),
+        }
+    }
+}
+
+fn is_dirty(repository: &Repository) -> Result<bool, git2::Error> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    Ok(!repository.statuses(Some(&mut options))?.is_empty())
+}
+
+/// Moves `branch` to `target`, the commit of its upstream, if this is a pure
+/// fast-forward.
+fn fast_forward_branch(
+    repository: &Repository,
+    branch: Branch,
+    target: Oid,
+) -> Result<BranchUpdate, git2::Error> {
+    let local = match branch.get().target() {
+        Some(local) => local,
+        None => return Ok(BranchUpdate::Skipped("symbolic ref".to_string())),
+    };
+    if local == target {
+        return Ok(BranchUpdate::UpToDate);
+    }
+    if repository.graph_descendant_of(local, target)? {
+        return Ok(BranchUpdate::Ahead);
+    }
+    if !repository.graph_descendant_of(target, local)? {
+        return Ok(BranchUpdate::Diverged);
+    }
+    if branch.is_head() && !repository.is_bare() {
+        if is_dirty(repository)? {
+            return Ok(BranchUpdate::Skipped("worktree is dirty".to_string()));
+        }
+        let commit = repository.find_commit(target)?;
+        repository.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+    }
+    branch.into_reference().set_target(target, "fast-forward")?;
+    Ok(BranchUpdate::Updated {
+        from: local,
+        to: target,
+    })
+}
+
+/// Moves every local branch that has an upstream to that upstream's commit,
+/// as long as this is a pure fast-forward. The checked out branch is only
+/// moved when the worktree is clean, in which case the worktree is updated
+/// along with it. A branch that can't be moved is reported as skipped with
+/// the reason and doesn't keep the others from moving.
+pub fn fast_forward_branches(
+    repository: &Repository,
+) -> Result<Vec<(String, BranchUpdate)>, git2::Error> {
+    let mut updates = Vec::new();
+    for branch in repository.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let name = String::from_utf8_lossy(branch.name_bytes()?).into_owned();
+        let upstream = match branch.upstream() {
+            Ok(upstream) => upstream,
+            Err(error) if error.code() == git2::ErrorCode::NotFound => continue,
+            Err(error) => {
+                updates.push((name, BranchUpdate::Skipped(error.message().to_string())));
+                continue;
+            }
+        };
+        let update = match upstream.get().target() {
+            Some(target) => fast_forward_branch(repository, branch, target)
+                .unwrap_or_else(|error| BranchUpdate::Skipped(error.message().to_string())),
+            None => BranchUpdate::Skipped("symbolic ref".to_string()),
+        };
+        debug!("Branch {}: {}", name, update);
+        updates.push((name, update));
+    }
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    fn commit(repository: &Repository, parent: Option<&git2::Commit>, file: &str) -> Oid {
+        let parent_tree = parent.map(|parent| parent.tree().unwrap());
+        let mut builder = repository.treebuilder(parent_tree.as_ref()).unwrap();
+        let blob = repository.blob(file.as_bytes()).unwrap();
+        builder.insert(file, blob, 0o100644).unwrap();
+        let tree = repository.find_tree(builder.write().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let parents: Vec<_> = parent.into_iter().collect();
+        repository
+            .commit(None, &signature, &signature, file, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_failing_branch_does_not_stop_others() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let remote = Repository::init(temp.child("remote").path()).unwrap();
+        let first = commit(&remote, None, "first");
+        remote
+            .reference("refs/heads/main", first, true, "test")
+            .unwrap();
+        remote.set_head("refs/heads/main").unwrap();
+        let local = Repository::clone(
+            temp.child("remote").to_str().unwrap(),
+            temp.child("local").path(),
+        )
+        .unwrap();
+        let first = local.find_commit(first).unwrap();
+        local
+            .branch("other", &first, false)
+            .unwrap()
+            .set_upstream(Some("origin/main"))
+            .unwrap();
+        let second = commit(
+            &remote,
+            Some(&remote.find_commit(first.id()).unwrap()),
+            "second",
+        );
+        remote
+            .reference("refs/heads/main", second, true, "test")
+            .unwrap();
+        local
+            .find_remote("origin")
+            .unwrap()
+            .fetch(&[] as &[&str], None, None)
+            .unwrap();
+        let third = commit(&local, Some(&local.find_commit(second).unwrap()), "third");
+        local
+            .branch("mine", &local.find_commit(third).unwrap(), false)
+            .unwrap()
+            .set_upstream(Some("origin/main"))
+            .unwrap();
+        // An untracked file the fast-forward of the checked out branch would
+        // overwrite.
+        temp.child("local/second").write_str("untracked").unwrap();
+
+        let updates = fast_forward_branches(&local).unwrap();
+        assert!(
+            matches!(&updates[0], (name, BranchUpdate::Skipped(_)) if name == "main"),
+            "{:?}",
+            updates
+        );
+        assert_eq!(updates[1], ("mine".to_string(), BranchUpdate::Ahead));
+        assert_eq!(
+            updates[2],
+            (
+                "other".to_string(),
+                BranchUpdate::Updated {
+                    from: first.id(),
+                    to: second
+                }
+            )
+        );
+    }
+}
@@ -0,0 +1,493 @@
+use crate::{
+    backend::{Backend, FetchBackend},
+    fast_forward::{self, BranchUpdate},
+    post_fetch::PostFetchOutcome,
+    precheck,
+    progress::{ProgressDisplay, TransferStats},
+    retry::RetryPolicy,
+    GitRepository,
+};
+use git2::Oid;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt};
+
+/// Remote fetched when neither the repository nor the config names one.
+pub const DEFAULT_REMOTE: &str = "origin";
+
+/// Refspec git uses to implement `--prune-tags`.
+const TAGS_REFSPEC: &str = "+refs/tags/*:refs/tags/*";
+
+/// Remote name in `GitRepository::remotes` that stands for every remote the
+/// repository has.
+pub const ALL_REMOTES: &str = "*";
+
+/// A remote to fetch, with refspecs of its own.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RemoteSpec {
+    /// Remote name, or `*` for every configured remote.
+    pub name: String,
+    /// Refspecs to fetch from this remote, defaults to
+    /// `GitRepository::fetch_branches`.
+    pub fetch_branches: Option<Vec<String>>,
+}
+
+/// Which tags to fetch along with the refspecs.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TagPolicy {
+    /// Tags pointing at commits that are fetched anyway.
+    Auto,
+    /// Every tag of the remote.
+    All,
+    /// No tags beyond those matched by the refspecs.
+    None,
+}
+
+impl From<TagPolicy> for git2::AutotagOption {
+    fn from(policy: TagPolicy) -> Self {
+        match policy {
+            TagPolicy::Auto => git2::AutotagOption::Auto,
+            TagPolicy::All => git2::AutotagOption::All,
+            TagPolicy::None => git2::AutotagOption::None,
+        }
+    }
+}
+
+/// How a ref moved during a fetch.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum RefChange {
+    Created,
+    /// Fast-forwarded by `ahead` commits; `behind` is always zero.
+    Updated {
+        ahead: usize,
+        behind: usize,
+    },
+    /// Rewritten: `ahead` new commits replace `behind` old ones.
+    ForceUpdated {
+        ahead: usize,
+        behind: usize,
+    },
+    /// Points to something else now, but the old and new targets can't be
+    /// compared, e.g. because one of them is not a commit.
+    Moved,
+    Deleted,
+}
+
+impl RefChange {
+    /// Classifies the move of a ref from `old` to `new`, zero ids meaning
+    /// the ref didn't exist before or after.
+    pub fn classify(repository: &git2::Repository, old: Oid, new: Oid) -> Self {
+        if old.is_zero() {
+            return RefChange::Created;
+        }
+        if new.is_zero() {
+            return RefChange::Deleted;
+        }
+        let peel = |oid| {
+            repository
+                .find_object(oid, None)
+                .and_then(|object| object.peel_to_commit())
+                .map(|commit| commit.id())
+        };
+        let ahead_behind = peel(old)
+            .and_then(|old| Ok((old, peel(new)?)))
+            .and_then(|(old, new)| repository.graph_ahead_behind(new, old));
+        match ahead_behind {
+            Ok((ahead, 0)) => RefChange::Updated { ahead, behind: 0 },
+            Ok((ahead, behind)) => RefChange::ForceUpdated { ahead, behind },
+            Err(_) => RefChange::Moved,
+        }
+    }
+}
+
+impl fmt::Display for RefChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefChange::Created => write!(f, "created"),
+            RefChange::Updated { ahead, .. } => write!(f, "updated (+{})", ahead),
+            RefChange::ForceUpdated { ahead, behind } => {
+                write!(f, "force-updated (+{} -{})", ahead, behind)
+            }
+            RefChange::Moved => write!(f, "moved"),
+            RefChange::Deleted => write!(f, "deleted"),
+        }
+    }
+}
+
+/// A ref moved by a fetch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdate {
+    pub name: String,
+    pub old: Oid,
+    pub new: Oid,
+    pub change: RefChange,
+}
+
+/// What fetching a single remote did.
+#[derive(Debug, Default)]
+pub struct RemoteOutcome {
+    pub name: String,
+    pub refspecs: Vec<String>,
+    /// Refs changed by the fetch, including pruned ones.
+    pub refs: Vec<RefUpdate>,
+    pub transfer: TransferStats,
+    /// The fetch was skipped because the remote advertised nothing new.
+    pub unchanged: bool,
+    pub error: Option<git2::Error>,
+}
+
+/// What a run did to a repository that could be opened.
+#[derive(Debug, Default)]
+pub struct FetchOutcome {
+    /// Data received while cloning the repository in this run.
+    pub clone_transfer: TransferStats,
+    pub remotes: Vec<RemoteOutcome>,
+    pub branches: Vec<(String, BranchUpdate)>,
+    /// Post-fetch commands run because refs changed.
+    pub post_fetch: Vec<PostFetchOutcome>,
+}
+
+impl FetchOutcome {
+    /// Whether every remote was fetched. Post-fetch commands don't count,
+    /// the fetch itself went through either way.
+    pub fn is_success(&self) -> bool {
+        self.remotes.iter().all(|remote| remote.error.is_none())
+    }
+
+    /// The post-fetch commands that ran and failed.
+    pub fn failed_post_fetch(&self) -> impl Iterator<Item = &PostFetchOutcome> {
+        self.post_fetch
+            .iter()
+            .filter(|command| !command.is_success())
+    }
+
+    /// Data received for the whole repository.
+    pub fn transfer(&self) -> TransferStats {
+        let mut total = self.clone_transfer;
+        for remote in &self.remotes {
+            total += remote.transfer;
+        }
+        total
+    }
+}
+
+/// Opens the repository at `local_path`, cloning it from `url` first if it
+/// does not exist yet. The clone's remote is named after the remote to fetch
+/// so the subsequent fetch finds it.
+fn open_or_clone(
+    backend: &dyn FetchBackend,
+    repository: &GitRepository,
+    progress: &ProgressDisplay,
+    outcome: &mut FetchOutcome,
+) -> Result<git2::Repository, git2::Error> {
+    let local_path = &repository.local_path;
+    match git2::Repository::open(local_path) {
+        Ok(git_repository) => Ok(git_repository),
+        Err(error) if error.code() == git2::ErrorCode::NotFound => match &repository.url {
+            Some(url) => {
+                info!("Cloning {} into {:?}", url, local_path);
+                backend.clone(repository, url, clone_remote(repository), progress, outcome)?;
+                git2::Repository::open(local_path)
+            }
+            None => Err(error),
+        },
+        Err(error) => Err(error),
+    }
+}
+
+/// Picks `origin`, or the repository's only remote if there is exactly one.
+pub fn default_remote(repository: &git2::Repository) -> Result<String, git2::Error> {
+    let remotes = repository.remotes()?;
+    let names: Vec<&str> = remotes.iter().flatten().collect();
+    match names.as_slice() {
+        names if names.contains(&DEFAULT_REMOTE) => Ok(DEFAULT_REMOTE.to_string()),
+        [name] => Ok(name.to_string()),
+        _ => Err(git2::Error::new(
+            git2::ErrorCode::NotFound,
+            git2::ErrorClass::Config,
+            "no remote configured and no unambiguous default",
+        )),
+    }
+}
+
+/// Name of the remote a fresh clone should create.
+fn clone_remote(repository: &GitRepository) -> &str {
+    repository
+        .remotes
+        .iter()
+        .map(|remote| remote.name.as_str())
+        .chain(repository.remote.as_deref())
+        .find(|name| *name != ALL_REMOTES)
+        .unwrap_or(DEFAULT_REMOTE)
+}
+
+/// Resolves which remotes to fetch and with which refspecs. Remotes listed
+/// by name take precedence over the same remote matched by `*`.
+fn remotes_to_fetch(
+    git_repository: &git2::Repository,
+    repository: &GitRepository,
+) -> Result<Vec<(String, Vec<String>)>, git2::Error> {
+    let default_refspecs = repository.fetch_branches.clone().unwrap_or_default();
+    if repository.remotes.is_empty() {
+        let name = match &repository.remote {
+            Some(remote) => remote.clone(),
+            None => default_remote(git_repository)?,
+        };
+        return Ok(vec![(name, default_refspecs)]);
+    }
+    let explicit: Vec<&str> = repository
+        .remotes
+        .iter()
+        .map(|remote| remote.name.as_str())
+        .filter(|name| *name != ALL_REMOTES)
+        .collect();
+    let mut remotes = Vec::new();
+    for spec in &repository.remotes {
+        let refspecs = spec
+            .fetch_branches
+            .clone()
+            .unwrap_or_else(|| default_refspecs.clone());
+        if spec.name == ALL_REMOTES {
+            for name in git_repository.remotes()?.iter().flatten() {
+                if !explicit.contains(&name) {
+                    remotes.push((name.to_string(), refspecs.clone()));
+                }
+            }
+        } else {
+            remotes.push((spec.name.clone(), refspecs));
+        }
+    }
+    Ok(remotes)
+}
+
+fn configured_refspecs(remote: &git2::Remote) -> Result<Vec<String>, git2::Error> {
+    Ok(remote
+        .fetch_refspecs()?
+        .iter()
+        .flatten()
+        .map(String::from)
+        .collect())
+}
+
+/// The refspecs handed to the backend for `remote`. Empty means the remote's
+/// configured refspecs.
+fn fetch_refspecs(
+    remote: &git2::Remote,
+    repository: &GitRepository,
+    refspecs: &[String],
+) -> Result<Vec<String>, git2::Error> {
+    let mut refspecs = refspecs.to_vec();
+    if repository.prune_tags {
+        // An explicit refspec replaces the configured ones, so spell those
+        // out before adding the tags.
+        if refspecs.is_empty() {
+            refspecs = configured_refspecs(remote)?;
+        }
+        refspecs.push(TAGS_REFSPEC.to_string());
+    }
+    Ok(refspecs)
+}
+
+/// A remote as it would be fetched.
+#[derive(Debug)]
+pub struct PlannedRemote {
+    pub name: String,
+    pub url: Option<String>,
+    pub refspecs: Vec<String>,
+}
+
+/// What a run would do to a repository, worked out without touching the
+/// network.
+#[derive(Debug)]
+pub enum Plan {
+    /// Clone from `url`, creating the remote `remote`, then fetch it.
+    Clone {
+        url: String,
+        remote: String,
+    },
+    Fetch(Vec<PlannedRemote>),
+}
+
+/// Works out what `handle_repository` would do for `repository`.
+pub fn plan(repository: &GitRepository) -> Result<Plan, git2::Error> {
+    let git_repository = match git2::Repository::open(&repository.local_path) {
+        Ok(git_repository) => git_repository,
+        Err(error) if error.code() == git2::ErrorCode::NotFound => {
+            return match &repository.url {
+                Some(url) => Ok(Plan::Clone {
+                    url: url.clone(),
+                    remote: clone_remote(repository).to_string(),
+                }),
+                None => Err(error),
+            };
+        }
+        Err(error) => return Err(error),
+    };
+    let mut remotes = Vec::new();
+    for (name, refspecs) in remotes_to_fetch(&git_repository, repository)? {
+        let remote = git_repository.find_remote(&name)?;
+        let mut refspecs = fetch_refspecs(&remote, repository, &refspecs)?;
+        if refspecs.is_empty() {
+            refspecs = configured_refspecs(&remote)?;
+        }
+        remotes.push(PlannedRemote {
+            name,
+            url: remote.url().map(String::from),
+            refspecs,
+        });
+    }
+    Ok(Plan::Fetch(remotes))
+}
+
+/// The object every direct ref of `git_repository` points to.
+pub fn ref_targets(git_repository: &git2::Repository) -> Result<HashMap<String, Oid>, git2::Error> {
+    let mut targets = HashMap::new();
+    for reference in git_repository.references()? {
+        let reference = reference?;
+        if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+            targets.insert(name.to_string(), target);
+        }
+    }
+    Ok(targets)
+}
+
+/// The refs that differ between `before` and `after`, sorted by name.
+pub fn ref_updates(
+    git_repository: &git2::Repository,
+    before: &HashMap<String, Oid>,
+    after: &HashMap<String, Oid>,
+) -> Vec<RefUpdate> {
+    let mut names: Vec<&String> = before.keys().chain(after.keys()).collect();
+    names.sort();
+    names.dedup();
+    names
+        .into_iter()
+        .filter_map(|name| {
+            let old = before.get(name).copied().unwrap_or_else(Oid::zero);
+            let new = after.get(name).copied().unwrap_or_else(Oid::zero);
+            (old != new).then(|| RefUpdate {
+                name: name.clone(),
+                old,
+                new,
+                change: RefChange::classify(git_repository, old, new),
+            })
+        })
+        .collect()
+}
+
+/// Whether fetching `refspecs` from the remote `name` would leave every
+/// local ref as it is, judged from the refs the remote advertises. Tags only
+/// count when all of them are fetched.
+fn is_up_to_date(
+    backend: &dyn FetchBackend,
+    git_repository: &git2::Repository,
+    repository: &GitRepository,
+    name: &str,
+    refspecs: &[String],
+) -> Result<bool, git2::Error> {
+    let configured = configured_refspecs(&git_repository.find_remote(name)?)?;
+    let mut refspecs = refspecs.to_vec();
+    if repository.tags == Some(TagPolicy::All)
+        && !refspecs.iter().any(|refspec| refspec == TAGS_REFSPEC)
+    {
+        if refspecs.is_empty() {
+            refspecs = configured.clone();
+        }
+        refspecs.push(TAGS_REFSPEC.to_string());
+    }
+    let advertised = backend.list(git_repository, repository, name)?;
+    Ok(precheck::is_unchanged(
+        &advertised,
+        &ref_targets(git_repository)?,
+        &refspecs,
+        &configured,
+        repository.prune || repository.prune_tags,
+    ))
+}
+
+/// Fetches the remote `outcome.name` and records the transfer and changed
+/// refs in `outcome`.
+fn fetch_remote(
+    backend: &dyn FetchBackend,
+    git_repository: &git2::Repository,
+    repository: &GitRepository,
+    progress: &ProgressDisplay,
+    outcome: &mut RemoteOutcome,
+) -> Result<(), git2::Error> {
+    let remote = git_repository.find_remote(&outcome.name)?;
+    let refspecs = fetch_refspecs(&remote, repository, &outcome.refspecs)?;
+    if repository.skip_unchanged.unwrap_or(false)
+        && is_up_to_date(
+            backend,
+            git_repository,
+            repository,
+            &outcome.name,
+            &refspecs,
+        )?
+    {
+        info!(
+            "{} of {:?} is unchanged, not fetching",
+            outcome.name, repository.local_path
+        );
+        outcome.unchanged = true;
+        return Ok(());
+    }
+    backend.fetch(git_repository, repository, &refspecs, progress, outcome)
+}
+
+/// Opens (or clones) the repository and fetches each of its remotes through
+/// the backend it selects, retrying transient failures according to
+/// `retry`. A failing remote doesn't stop the others; its error is recorded
+/// in the outcome instead. If any ref changed, the post-fetch commands run
+/// last.
+pub fn handle_repository(
+    repository: &GitRepository,
+    retry: &RetryPolicy,
+    progress: &ProgressDisplay,
+) -> Result<FetchOutcome, git2::Error> {
+    let backend = Backend::select(repository).implementation();
+    let mut outcome = FetchOutcome::default();
+    let git_repository =
+        retry.run(|| open_or_clone(backend, repository, progress, &mut outcome))?;
+    for (name, refspecs) in remotes_to_fetch(&git_repository, repository)? {
+        let mut remote = RemoteOutcome {
+            name,
+            refspecs,
+            ..Default::default()
+        };
+        let result =
+            retry.run(|| fetch_remote(backend, &git_repository, repository, progress, &mut remote));
+        if let Err(error) = result {
+            warn!(
+                "Fetching {} of {:?} failed: {}",
+                remote.name, repository.local_path, error
+            );
+            remote.error = Some(error);
+        }
+        outcome.remotes.push(remote);
+    }
+    if repository.fast_forward {
+        match fast_forward::fast_forward_branches(&git_repository) {
+            Ok(branches) => outcome.branches = branches,
+            Err(error) => warn!(
+                "Cannot fast-forward the branches of {:?}: {}",
+                repository.local_path, error
+            ),
+        }
+    }
+    let changed: Vec<&RefUpdate> = outcome
+        .remotes
+        .iter()
+        .flat_map(|remote| &remote.refs)
+        .collect();
+    if !changed.is_empty() {
+        outcome.post_fetch = repository
+            .post_fetch
+            .iter()
+            .map(|command| command.run(repository, &changed))
+            .collect();
+    }
+    Ok(outcome)
+}
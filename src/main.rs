@@ -1,38 +1,329 @@
-use anyhow::Result;
-use log::{debug, trace, LevelFilter};
+mod auth;
+mod backend;
+mod check;
+mod edit;
+mod fast_forward;
+mod fetch;
+mod pool;
+mod post_fetch;
+mod precheck;
+mod progress;
+mod report;
+mod retry;
+mod scan;
+mod state;
+mod status;
+
+use anyhow::{bail, Context, Result};
+use auth::Credentials;
+use backend::Backend;
+use check::Problem;
+use fetch::{Plan, RemoteSpec, TagPolicy};
+use log::{debug, error, info, trace, warn, LevelFilter};
+use log4rs::append::console::Target;
+use post_fetch::{PostFetch, PostFetchStatus};
+use progress::ProgressDisplay;
+use report::{ReportFormat, RepositoryResult};
+use retry::RetryPolicy;
+use scan::ScanRoot;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, thread};
+use state::StateStore;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
+/// Every repository was fetched successfully.
+const EXIT_OK: i32 = 0;
+/// At least one repository failed; the others were still processed.
+const EXIT_FETCH_FAILED: i32 = 1;
+/// The config file could not be loaded, nothing was fetched.
+const EXIT_CONFIG_INVALID: i32 = 2;
+/// An `add` or `remove` that doesn't fit the config file, which is left as
+/// it is.
+const EXIT_USAGE: i32 = 3;
+
+/// Names tried, in order, in each config directory.
+const CONFIG_FILE_NAMES: [&str; 3] = ["config.toml", "config.yaml", "config.json"];
+
+/// Directory next to the config file whose fragments are merged on top of it.
+const FRAGMENT_DIRECTORY: &str = "config.d";
+
+/// Seconds between fetches in daemon mode when the config sets no interval.
+const DEFAULT_DAEMON_INTERVAL: u64 = 300;
+
 #[derive(StructOpt, Debug)]
-#[structopt()]
+#[structopt(after_help = "EXIT STATUS:
+    0  every repository was fetched successfully
+    1  at least one repository failed
+    2  the config file is invalid
+    3  add or remove cannot be applied to the config file")]
 struct CliArgs {
+    /// Config file; defaults to `$GIT_AUTO_FETCH_CONFIG`, then
+    /// `config.{toml,yaml,json}` in `$XDG_CONFIG_HOME/git-auto-fetch` or
+    /// `~/.config/git-auto-fetch`.
     #[structopt(short, long, parse(from_os_str))]
-    config_file: PathBuf,
+    config_file: Option<PathBuf>,
 
     #[structopt(short, long, default_value = "info")]
     log_level: LevelFilter,
+
+    /// Write a machine-readable report of the run, `json` or `jsonl`.
+    #[structopt(long)]
+    report: Option<ReportFormat>,
+
+    /// Show what would be fetched, and how, without touching the network.
+    /// With `add` or `remove`, print the edited config file instead of
+    /// writing it.
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Print the effective config, after merging includes and `config.d`,
+    /// and exit.
+    #[structopt(long)]
+    print_config: bool,
+
+    /// File to write the report to instead of stdout.
+    #[structopt(long, parse(from_os_str), requires = "report")]
+    report_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Fetch every repository once and exit, ignoring any fetch interval.
+    Run,
+    /// Keep fetching every repository on its interval.
+    Daemon,
+    /// Show the configured repositories and what is fetched from them.
+    List,
+    /// Show when each repository was last fetched and how its branches
+    /// compare to their upstream.
+    Status,
+    /// Add a repository to the config file. Only TOML and JSON config files
+    /// can be edited, YAML ones are left to edit by hand.
+    Add {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Remote to fetch.
+        #[structopt(long)]
+        remote: Option<String>,
+        /// URL to clone from if `path` does not exist yet.
+        #[structopt(long)]
+        url: Option<String>,
+        /// Branch or refspec to fetch; may be given more than once.
+        #[structopt(long = "branch")]
+        fetch_branches: Vec<String>,
+    },
+    /// Remove a repository from the config file. Only TOML and JSON config
+    /// files can be edited.
+    Remove {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// Validate the config file and the repositories it names without
+    /// fetching anything.
+    #[structopt(alias = "validate")]
+    Check,
+}
+
+/// `path` with symlinks and `..` resolved, so two spellings of the same
+/// repository compare equal. A path that doesn't exist yet is kept as it is.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Where in the config files an entry was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub file: PathBuf,
+    /// Position of the entry in its list in `file`.
+    pub index: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
 pub struct GitRepository {
+    /// Where this entry was written; `None` for discovered repositories.
+    #[serde(skip)]
+    source: Option<Source>,
     local_path: PathBuf,
-    remote: String,
-    fetch_branches: Vec<String>,
+    /// Remote to fetch. Defaults to `Config::remote`, then to `origin` or the
+    /// repository's only remote.
+    remote: Option<String>,
+    /// Refspecs to fetch. Defaults to `Config::fetch_branches`; an empty list
+    /// fetches the remote's configured refspecs like a plain `git fetch`.
+    fetch_branches: Option<Vec<String>>,
+    /// Remotes to fetch, each with optional refspecs of its own. A remote
+    /// named `*` stands for all of the repository's remotes. Takes precedence
+    /// over `remote` when not empty.
+    #[serde(default)]
+    remotes: Vec<RemoteSpec>,
+    /// URL to clone from when `local_path` does not contain a repository yet.
+    url: Option<String>,
+    /// Seconds between fetches in daemon mode, overriding `Config::fetch_interval`.
+    fetch_interval: Option<u64>,
+    /// How to authenticate against `remote`; libgit2's defaults if unset.
+    credentials: Option<Credentials>,
+    /// Retry policy for transient failures, overriding `Config::retry`.
+    retry: Option<RetryPolicy>,
+    /// Fast-forward local branches to their upstream after fetching.
+    #[serde(default)]
+    fast_forward: bool,
+    /// Delete remote-tracking refs whose branch is gone from the remote.
+    #[serde(default)]
+    prune: bool,
+    /// Delete local tags that are gone from the remote. Implies fetching all
+    /// tags, like `git fetch --prune-tags`, and also `prune`: git prunes tags
+    /// in the same pass as remote-tracking refs, so this deletes those too.
+    #[serde(default)]
+    prune_tags: bool,
+    /// Which tags to fetch; the remote's `tagOpt` setting applies if unset.
+    tags: Option<TagPolicy>,
+    /// List the remote's refs first and skip the fetch if none of the
+    /// fetched ones moved. Defaults to `Config::skip_unchanged`.
+    skip_unchanged: Option<bool>,
+    /// Fetch only this many commits of history, like `git fetch --depth`.
+    depth: Option<u32>,
+    /// Fetch the complete history of a shallow repository.
+    #[serde(default)]
+    unshallow: bool,
+    /// Partial clone filter such as `blob:none` or `tree:0`.
+    filter: Option<String>,
+    /// Whether to fetch with libgit2 or the `git` executable. Defaults to
+    /// `Config::backend`, then libgit2; shallow and partial fetches always
+    /// use `git`, which shows no live progress, and setting `libgit2` here
+    /// together with them is an error.
+    backend: Option<Backend>,
+    /// Commands run after a fetch that changed refs, after those of
+    /// `Config::post_fetch`.
+    #[serde(default)]
+    post_fetch: Vec<PostFetch>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl GitRepository {
+    /// Returns how long to wait between fetches, or `None` to fetch only once.
+    fn fetch_interval(&self, default: Option<u64>) -> Option<Duration> {
+        self.fetch_interval.or(default).map(Duration::from_secs)
+    }
+
+    fn retry_policy(&self, default: Option<&RetryPolicy>) -> RetryPolicy {
+        self.retry.as_ref().or(default).cloned().unwrap_or_default()
+    }
+
+    /// Fills in the settings this entry leaves to the config-level defaults.
+    fn inherit(&mut self, config: &Config) {
+        if self.remote.is_none() {
+            self.remote = config.remote.clone();
+        }
+        if self.fetch_branches.is_none() {
+            self.fetch_branches = config.fetch_branches.clone();
+        }
+        if self.skip_unchanged.is_none() {
+            self.skip_unchanged = config.skip_unchanged;
+        }
+        if self.backend.is_none() {
+            self.backend = config.backend;
+        }
+        let own = std::mem::take(&mut self.post_fetch);
+        self.post_fetch = config
+            .post_fetch
+            .iter()
+            .flatten()
+            .cloned()
+            .chain(own)
+            .collect();
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct Config {
+    /// Further config files this one is layered on top of, relative to it.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    include: Vec<PathBuf>,
+    #[serde(default)]
     repositories: Vec<GitRepository>,
+    /// Directories searched for further repositories.
+    #[serde(default)]
+    scan_roots: Vec<ScanRoot>,
+    /// Default seconds between fetches. Without any interval every repository
+    /// is fetched once and the program exits.
+    fetch_interval: Option<u64>,
+    /// Maximum number of repositories fetched at the same time. Defaults to
+    /// the number of CPUs.
+    max_concurrency: Option<usize>,
+    /// Default retry policy for transient failures.
+    retry: Option<RetryPolicy>,
+    /// Default remote for repositories that don't name one.
+    remote: Option<String>,
+    /// Default refspecs for repositories that don't list any.
+    fetch_branches: Option<Vec<String>>,
+    /// Default for `GitRepository::skip_unchanged`.
+    skip_unchanged: Option<bool>,
+    /// Default for `GitRepository::backend`.
+    backend: Option<Backend>,
+    /// Commands run for every repository whose refs a fetch changed. A later
+    /// layer that sets this replaces the list; `[]` clears it.
+    post_fetch: Option<Vec<PostFetch>>,
+    /// Where fetch results are remembered between runs. Defaults to
+    /// `$XDG_STATE_HOME/git-auto-fetch/state.json`.
+    state_file: Option<PathBuf>,
+    /// Problems found in the settings of each file while loading it.
+    #[serde(skip)]
+    problems: Vec<Problem>,
+}
+
+impl Config {
+    /// Layers `other` on top of this config. Settings `other` makes replace
+    /// these, its repositories are appended, and an entry for a `local_path`
+    /// that is already listed, by an earlier layer or earlier in `other`,
+    /// replaces that entry in place.
+    fn merge(&mut self, other: Config) {
+        let mut known: HashMap<PathBuf, usize> = self
+            .repositories
+            .iter()
+            .enumerate()
+            .map(|(index, repository)| (canonical_path(&repository.local_path), index))
+            .collect();
+        for repository in other.repositories {
+            match known.entry(canonical_path(&repository.local_path)) {
+                Entry::Occupied(entry) => self.repositories[*entry.get()] = repository,
+                Entry::Vacant(entry) => {
+                    entry.insert(self.repositories.len());
+                    self.repositories.push(repository);
+                }
+            }
+        }
+        self.scan_roots.extend(other.scan_roots);
+        self.fetch_interval = other.fetch_interval.or(self.fetch_interval);
+        self.max_concurrency = other.max_concurrency.or(self.max_concurrency);
+        self.retry = other.retry.or(self.retry.take());
+        self.remote = other.remote.or(self.remote.take());
+        self.fetch_branches = other.fetch_branches.or(self.fetch_branches.take());
+        self.skip_unchanged = other.skip_unchanged.or(self.skip_unchanged);
+        self.backend = other.backend.or(self.backend);
+        self.post_fetch = other.post_fetch.or(self.post_fetch.take());
+        self.state_file = other.state_file.or(self.state_file.take());
+        self.problems.extend(other.problems);
+    }
 }
 
-fn init_logging(log_level: LevelFilter) -> Result<()> {
+fn init_logging(log_level: LevelFilter, target: Target) -> Result<()> {
     use log4rs::{
-        append::console::ConsoleAppender,
         config::{Appender, Config, Root},
         Handle,
     };
-    let stdout = ConsoleAppender::builder().build();
+    let stdout = progress::LogAppender::new(target);
     let config = Config::builder()
         .appender(Appender::builder().build("stdout", Box::new(stdout)))
         .build(Root::builder().appender("stdout").build(log_level));
@@ -40,45 +331,550 @@ fn init_logging(log_level: LevelFilter) -> Result<()> {
     Ok(())
 }
 
-fn load_config(config_file: PathBuf) -> Result<Config> {
+/// Finds the config file to use when none was given on the command line;
+/// `var` looks up environment variables.
+fn default_config_file(var: impl Fn(&str) -> Option<OsString>) -> Result<PathBuf> {
+    if let Some(config_file) = var("GIT_AUTO_FETCH_CONFIG").filter(|value| !value.is_empty()) {
+        return Ok(PathBuf::from(config_file));
+    }
+    let xdg_config_home = var("XDG_CONFIG_HOME")
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from);
+    let home_config = var("HOME")
+        .filter(|value| !value.is_empty())
+        .map(|home| PathBuf::from(home).join(".config"));
+    let directories: Vec<PathBuf> = xdg_config_home
+        .into_iter()
+        .chain(home_config)
+        .map(|directory| directory.join("git-auto-fetch"))
+        .collect();
+    directories
+        .iter()
+        .flat_map(|directory| {
+            CONFIG_FILE_NAMES
+                .iter()
+                .map(move |name| directory.join(name))
+        })
+        .find(|config_file| config_file.is_file())
+        .with_context(|| {
+            format!(
+                "no config file given and none of {} found in {:?}",
+                CONFIG_FILE_NAMES.join(", "),
+                directories
+            )
+        })
+}
+
+fn read_config_file(config_file: &Path) -> Result<Config> {
     let mut settings = config::Config::default();
     settings.merge(config::File::from(config_file))?;
     let config = settings.try_into()?;
     Ok(config)
 }
 
-fn handle_repository(repository: GitRepository) {
-    let GitRepository {
-        local_path,
-        remote,
-        fetch_branches,
-    } = repository;
-    let repository = git2::Repository::open(local_path).unwrap();
-    let remote = repository.find_remote(&remote);
-    let result = match remote {
-        Ok(mut remote) => remote.fetch(&fetch_branches, None, None),
-        Err(error) => Err(error),
-    };
-    result.unwrap()
+/// Loads `config_file` on top of the files it includes. `loading` holds the
+/// files currently being loaded, to catch include cycles.
+fn load_layers(config_file: &Path, loading: &mut Vec<PathBuf>) -> Result<Config> {
+    let canonical =
+        fs::canonicalize(config_file).with_context(|| format!("cannot read {:?}", config_file))?;
+    if loading.contains(&canonical) {
+        bail!("{:?} is included in a cycle", config_file);
+    }
+    let mut file =
+        read_config_file(config_file).with_context(|| config_file.display().to_string())?;
+    for (index, repository) in file.repositories.iter_mut().enumerate() {
+        repository.source = Some(Source {
+            file: config_file.to_path_buf(),
+            index,
+        });
+    }
+    for (index, scan_root) in file.scan_roots.iter_mut().enumerate() {
+        scan_root.source = Some(Source {
+            file: config_file.to_path_buf(),
+            index,
+        });
+    }
+    file.problems = check::check_settings(&file, config_file);
+    let directory = config_file.parent().unwrap_or_else(|| Path::new(""));
+    let mut config = Config::default();
+    loading.push(canonical);
+    for include in &file.include {
+        debug!("{:?} includes {:?}", config_file, include);
+        config.merge(load_layers(&directory.join(include), loading)?);
+    }
+    loading.pop();
+    config.merge(file);
+    Ok(config)
+}
+
+/// Config files in the `config.d` directory next to `config_file`, in the
+/// order they are merged.
+fn fragments(config_file: &Path) -> Result<Vec<PathBuf>> {
+    let directory = config_file
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(FRAGMENT_DIRECTORY);
+    if !directory.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut fragments = Vec::new();
+    for entry in fs::read_dir(&directory)? {
+        let path = entry?.path();
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        if path.is_file() && matches!(extension, Some("toml" | "yaml" | "yml" | "json")) {
+            fragments.push(path);
+        }
+    }
+    fragments.sort();
+    Ok(fragments)
+}
+
+/// Loads `config_file` with its includes, then merges the fragments in
+/// `config.d` on top of it.
+fn load_config(config_file: PathBuf) -> Result<Config> {
+    let mut config = load_layers(&config_file, &mut Vec::new())?;
+    for fragment in fragments(&config_file)? {
+        debug!("Merging {:?}", fragment);
+        config.merge(load_layers(&fragment, &mut Vec::new())?);
+    }
+    Ok(config)
+}
+
+/// Combines the explicitly configured repositories with those found below
+/// `scan_roots`, with the config-level defaults filled in.
+fn discover_repositories(config: &Config) -> Result<Vec<GitRepository>> {
+    let mut repositories = config.repositories.clone();
+    for scan_root in &config.scan_roots {
+        scan::merge(&mut repositories, scan_root.discover()?);
+    }
+    for repository in &mut repositories {
+        repository.inherit(config);
+    }
+    Ok(repositories)
+}
+
+/// Logs every problem in the config and returns the process exit code.
+fn check(config_file: &Path, config: &Config) -> i32 {
+    let problems = check::check_config(config);
+    for problem in &problems {
+        error!("{}", problem);
+    }
+    if problems.is_empty() {
+        info!("{}: ok", config_file.display());
+        EXIT_OK
+    } else {
+        error!("{}: {} problems", config_file.display(), problems.len());
+        EXIT_CONFIG_INVALID
+    }
+}
+
+/// Prints what a run would do to each repository and returns the process
+/// exit code.
+fn dry_run(repositories: &[GitRepository], config: &Config) -> i32 {
+    let mut exit_code = EXIT_OK;
+    for repository in repositories {
+        println!("{}", repository.local_path.display());
+        match fetch::plan(repository) {
+            Ok(Plan::Clone { url, remote }) => {
+                println!("  clone {} as remote {}", url, remote);
+            }
+            Ok(Plan::Fetch(remotes)) => {
+                for remote in remotes {
+                    println!(
+                        "  remote {} ({})",
+                        remote.name,
+                        remote.url.as_deref().unwrap_or("no url")
+                    );
+                    for refspec in &remote.refspecs {
+                        println!("    {}", refspec);
+                    }
+                }
+            }
+            Err(error) => {
+                println!("  error: {}", error);
+                exit_code = EXIT_FETCH_FAILED;
+            }
+        }
+        let retry = repository.retry_policy(config.retry.as_ref());
+        println!(
+            "  tags: {:?}, prune: {}, prune_tags: {}, fast_forward: {}, skip_unchanged: {}",
+            repository.tags,
+            repository.prune,
+            repository.prune_tags,
+            repository.fast_forward,
+            repository.skip_unchanged.unwrap_or(false)
+        );
+        println!(
+            "  backend: {:?}, depth: {:?}, unshallow: {}, filter: {:?}",
+            Backend::select(repository),
+            repository.depth,
+            repository.unshallow,
+            repository.filter
+        );
+        for command in &repository.post_fetch {
+            println!("  post_fetch: {}", command.command);
+        }
+        println!(
+            "  interval: {:?}, retry: {} attempts, credentials: {:?}",
+            repository.fetch_interval(config.fetch_interval),
+            retry.max_attempts,
+            repository.credentials
+        );
+    }
+    exit_code
+}
+
+/// Prints each repository with the remotes and refspecs it is configured
+/// to fetch.
+fn list(repositories: &[GitRepository]) {
+    for repository in repositories {
+        let remotes: Vec<String> = if repository.remotes.is_empty() {
+            vec![repository
+                .remote
+                .clone()
+                .unwrap_or_else(|| "(default)".to_string())]
+        } else {
+            repository
+                .remotes
+                .iter()
+                .map(|remote| remote.name.clone())
+                .collect()
+        };
+        let refspecs = match &repository.fetch_branches {
+            Some(branches) if !branches.is_empty() => branches.join(","),
+            _ => "(configured)".to_string(),
+        };
+        println!(
+            "{}\t{}\t{}",
+            repository.local_path.display(),
+            remotes.join(","),
+            refspecs
+        );
+    }
+}
+
+/// Fetches every repository, again after its interval unless `once` is
+/// set, and returns the results of the last fetch of each. Every result is
+/// recorded in `store`, which is flushed every few seconds and at the end;
+/// when fetching on an interval, a repository that was fetched recently
+/// waits for the rest of its interval first.
+fn fetch_all(
+    repositories: &[GitRepository],
+    config: Config,
+    store: &StateStore,
+    once: bool,
+) -> Vec<RepositoryResult> {
+    let Config {
+        fetch_interval,
+        max_concurrency,
+        retry,
+        ..
+    } = config;
+    let workers = max_concurrency.unwrap_or_else(pool::default_workers);
+    debug!("Fetching with {} workers", workers);
+    let progress = ProgressDisplay::new(io::stderr().is_terminal());
+    let (stop_flushing, stopped) = mpsc::channel::<()>();
+    let results = thread::scope(|scope| {
+        scope.spawn(move || store.flush_until(stopped));
+        let results = pool::run(
+            repositories,
+            workers,
+            |repository| {
+                let interval = match repository.fetch_interval(fetch_interval) {
+                    Some(interval) if !once => interval,
+                    _ => return Duration::ZERO,
+                };
+                let last_attempt = store
+                    .get(&repository.local_path)
+                    .and_then(|state| state.last_attempt);
+                let delay = last_attempt.map_or(Duration::ZERO, |last_attempt| {
+                    interval.saturating_sub(state::age(last_attempt))
+                });
+                if !delay.is_zero() {
+                    info!(
+                        "{:?} was fetched recently, next fetch in {:?}",
+                        repository.local_path, delay
+                    );
+                }
+                delay
+            },
+            |repository| {
+                let start = Instant::now();
+                let result = fetch::handle_repository(
+                    repository,
+                    &repository.retry_policy(retry.as_ref()),
+                    &progress,
+                );
+                if let Err(error) = &result {
+                    warn!("Fetching {:?} failed: {}", repository.local_path, error);
+                }
+                let result = RepositoryResult {
+                    local_path: repository.local_path.clone(),
+                    duration: start.elapsed(),
+                    result,
+                };
+                store.record(&result);
+                result
+            },
+            |repository, _| {
+                if once {
+                    return None;
+                }
+                let interval = repository.fetch_interval(fetch_interval)?;
+                debug!(
+                    "Next fetch of {:?} in {:?}",
+                    repository.local_path, interval
+                );
+                Some(interval)
+            },
+        );
+        drop(stop_flushing);
+        results
+    });
+    repositories
+        .iter()
+        .zip(results)
+        .map(|(repository, result)| {
+            result.unwrap_or_else(|_| RepositoryResult {
+                local_path: repository.local_path.clone(),
+                duration: Duration::default(),
+                result: Err(git2::Error::from_str("worker thread panicked")),
+            })
+        })
+        .collect()
+}
+
+/// Logs the outcome of every repository and returns the process exit code,
+/// which failed post-fetch commands don't change.
+fn report_results(results: &[RepositoryResult]) -> i32 {
+    let failed = results.iter().filter(|result| !result.is_success()).count();
+    for RepositoryResult {
+        local_path, result, ..
+    } in results
+    {
+        match result {
+            Ok(outcome) => {
+                for remote in &outcome.remotes {
+                    debug!(
+                        "{:?}: remote {} refspecs {:?}",
+                        local_path, remote.name, remote.refspecs
+                    );
+                    match &remote.error {
+                        None if remote.unchanged => {
+                            info!("{:?}: remote {} unchanged", local_path, remote.name)
+                        }
+                        None => info!("{:?}: remote {} ok", local_path, remote.name),
+                        Some(error) => {
+                            error!("{:?}: remote {}: {}", local_path, remote.name, error)
+                        }
+                    }
+                    for update in &remote.refs {
+                        info!("{:?}: {} {}", local_path, update.name, update.change);
+                    }
+                }
+                let transfer = outcome.transfer();
+                info!(
+                    "{:?}: received {} objects, {}",
+                    local_path,
+                    transfer.objects,
+                    progress::format_bytes(transfer.bytes)
+                );
+                for (branch, update) in &outcome.branches {
+                    info!("{:?}: branch {} {}", local_path, branch, update);
+                }
+                for command in &outcome.post_fetch {
+                    match &command.status {
+                        PostFetchStatus::Exited(0) => {
+                            info!("{:?}: post_fetch {:?} ok", local_path, command.command)
+                        }
+                        status => warn!(
+                            "{:?}: post_fetch {:?}: {:?}",
+                            local_path, command.command, status
+                        ),
+                    }
+                }
+            }
+            Err(error) => error!("{:?}: {}", local_path, error),
+        }
+    }
+    info!(
+        "Fetched {} of {} repositories",
+        results.len() - failed,
+        results.len()
+    );
+    if failed == 0 {
+        EXIT_OK
+    } else {
+        EXIT_FETCH_FAILED
+    }
+}
+
+/// Writes the machine-readable report to `report_file`, or stdout.
+fn write_report(
+    results: &[RepositoryResult],
+    format: ReportFormat,
+    report_file: Option<PathBuf>,
+) -> Result<()> {
+    match report_file {
+        Some(report_file) => report::write_report(
+            results,
+            format,
+            io::BufWriter::new(fs::File::create(report_file)?),
+        ),
+        None => report::write_report(results, format, io::stdout().lock()),
+    }
+}
+
+/// Contents of the config file with an `add` or `remove` command applied.
+fn edit_config(config_file: &Path, command: &Command) -> Result<String> {
+    match command {
+        Command::Add {
+            path,
+            remote,
+            url,
+            fetch_branches,
+        } => edit::add_repository(
+            config_file,
+            &edit::NewRepository {
+                local_path: std::path::absolute(path)?,
+                remote: remote.clone(),
+                url: url.clone(),
+                fetch_branches: fetch_branches.clone(),
+            },
+        ),
+        Command::Remove { path } => {
+            edit::remove_repository(config_file, &std::path::absolute(path)?)
+        }
+        _ => unreachable!("only add and remove edit the config file"),
+    }
 }
 
 fn main() {
     let CliArgs {
         config_file,
         log_level,
+        report,
+        report_file,
+        dry_run: is_dry_run,
+        print_config,
+        command,
     } = CliArgs::from_args();
-    let logger_init_result = init_logging(log_level);
+    // Keep stdout free for the report and for listings.
+    let log_target = match (report, &report_file) {
+        (Some(_), None) => Target::Stderr,
+        _ if is_dry_run || print_config => Target::Stderr,
+        _ if matches!(command, Some(Command::List) | Some(Command::Status)) => Target::Stderr,
+        _ => Target::Stdout,
+    };
+    let logger_init_result = init_logging(log_level, log_target);
     trace!("Initialized logger {:?}", logger_init_result);
-    let config = load_config(config_file).unwrap();
+    let config_file =
+        match config_file.map_or_else(|| default_config_file(|name| env::var_os(name)), Ok) {
+            Ok(config_file) => config_file,
+            Err(error) => {
+                error!("{:#}", error);
+                process::exit(EXIT_CONFIG_INVALID);
+            }
+        };
+    info!("Using config file {}", config_file.display());
+    let edited = match command {
+        Some(Command::Add { .. }) | Some(Command::Remove { .. }) => {
+            Some(edit_config(&config_file, command.as_ref().unwrap()))
+        }
+        _ => None,
+    };
+    match edited {
+        // A dry run never writes; show what would be written instead.
+        Some(Ok(text)) if is_dry_run || print_config => {
+            info!("Not updating {} in a dry run", config_file.display());
+            print!("{}", text);
+            process::exit(EXIT_OK);
+        }
+        Some(Ok(text)) => {
+            if let Err(error) = fs::write(&config_file, text) {
+                error!("Cannot update {}: {}", config_file.display(), error);
+                process::exit(EXIT_CONFIG_INVALID);
+            }
+            info!("Updated {}", config_file.display());
+            process::exit(EXIT_OK);
+        }
+        Some(Err(error)) => {
+            error!("Cannot update {}: {:#}", config_file.display(), error);
+            if error.is::<edit::UsageError>() {
+                process::exit(EXIT_USAGE);
+            }
+            process::exit(EXIT_CONFIG_INVALID);
+        }
+        None => (),
+    }
+    let mut config = match load_config(config_file.clone()) {
+        Ok(config) => config,
+        Err(error) if matches!(command, Some(Command::Check)) => {
+            error!("{:#}", error);
+            error!("{}: cannot be loaded", config_file.display());
+            process::exit(EXIT_CONFIG_INVALID);
+        }
+        Err(error) => {
+            error!("Invalid config: {:#}", error);
+            process::exit(EXIT_CONFIG_INVALID);
+        }
+    };
     debug!("Loaded config {:?}", config);
-    let Config { repositories } = config;
-    let mut handles = Vec::new();
-    for repository in repositories {
-        handles.push(thread::spawn(move || handle_repository(repository)));
+    if print_config {
+        match serde_json::to_string_pretty(&config) {
+            Ok(config) => println!("{}", config),
+            Err(error) => error!("Cannot print config: {}", error),
+        }
+        process::exit(EXIT_OK);
     }
-    handles.into_iter().for_each(|cur_thread| {
-        cur_thread.join().unwrap();
-    });
+    if let Some(Command::Check) = command {
+        process::exit(check(&config_file, &config));
+    }
+    if !config.problems.is_empty() {
+        for problem in &config.problems {
+            error!("Invalid config: {}", problem);
+        }
+        process::exit(EXIT_CONFIG_INVALID);
+    }
+    let repositories = match discover_repositories(&config) {
+        Ok(repositories) => repositories,
+        Err(error) => {
+            error!("Invalid config: {:#}", error);
+            process::exit(EXIT_CONFIG_INVALID);
+        }
+    };
+    if is_dry_run {
+        process::exit(dry_run(&repositories, &config));
+    }
+    let store = StateStore::open(
+        config
+            .state_file
+            .clone()
+            .or_else(|| state::default_path(|name| env::var_os(name))),
+    );
+    match command {
+        Some(Command::List) => {
+            list(&repositories);
+            process::exit(EXIT_OK);
+        }
+        Some(Command::Status) => {
+            status::print_status(&repositories, &store);
+            process::exit(EXIT_OK);
+        }
+        Some(Command::Daemon) => {
+            config.fetch_interval.get_or_insert(DEFAULT_DAEMON_INTERVAL);
+        }
+        _ => (),
+    }
+    let once = matches!(command, Some(Command::Run));
+    let results = fetch_all(&repositories, config, &store, once);
+    let exit_code = report_results(&results);
+    if let Some(format) = report {
+        if let Err(error) = write_report(&results, format, report_file) {
+            error!("Cannot write report: {:#}", error);
+        }
+    }
+    process::exit(exit_code);
 }
 
 #[cfg(test)]
@@ -86,44 +882,82 @@ mod tests {
     use super::*;
     use assert_cmd::Command;
     use assert_fs::prelude::*;
+    use predicates::prelude::PredicateBooleanExt;
 
-    fn command() -> assert_cmd::Command {
-        Command::cargo_bin("git-auto-fetch").unwrap()
+    /// The program, remembering its state below `temp`.
+    fn command(temp: &assert_fs::TempDir) -> assert_cmd::Command {
+        let mut command = Command::cargo_bin("git-auto-fetch").unwrap();
+        command.env("XDG_STATE_HOME", temp.path().join("state"));
+        command
+    }
+
+    /// URL of the repository in `dir`, as a remote of another one.
+    fn file_url(dir: &Path) -> String {
+        format!("file://{}/.git", dir.to_str().unwrap())
+    }
+
+    /// Writes `config` to `config.json` in `temp`.
+    fn write_config(temp: &assert_fs::TempDir, config: &Config) -> assert_fs::fixture::ChildPath {
+        let config_file = temp.child("config.json");
+        config_file
+            .write_str(&serde_json::to_string(config).unwrap())
+            .unwrap();
+        config_file
+    }
+
+    fn commit(repository: &git2::Repository, message: &str) -> git2::Oid {
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree_id = repository.index().unwrap().write_tree().unwrap();
+        let tree = repository.find_tree(tree_id).unwrap();
+        let parent = repository
+            .head()
+            .ok()
+            .map(|head| head.peel_to_commit().unwrap());
+        let parents: Vec<_> = parent.iter().collect();
+        repository
+            .commit(
+                Some("HEAD"),
+                &signature,
+                &signature,
+                message,
+                &tree,
+                &parents,
+            )
+            .unwrap()
     }
 
     #[test]
     fn test_logging() {
-        let mut cmd = command();
-        let config = serde_json::to_string(&Config {
-            repositories: Vec::new(),
-        })
-        .unwrap();
         let temp = assert_fs::TempDir::new().unwrap();
-        let config_file = temp.child("config.json");
-        config_file.write_str(&config).unwrap();
+        let mut cmd = command(&temp);
+        let config_file = write_config(&temp, &Config::default());
         let assert = cmd.arg("--config-file").arg(config_file.path()).assert();
         assert.code(0);
     }
 
     #[test]
     fn test_fetch() {
-        let mut cmd = command();
         let temp = assert_fs::TempDir::new().unwrap();
+        let mut cmd = command(&temp);
         let remote_name = "origin";
         let local_dir = temp.child("local");
         let remote_dir = temp.child("remote");
-        let remote_repository = git2::Repository::init(remote_dir.to_path_buf()).unwrap();
-        let local_repository = git2::Repository::init(local_dir.to_path_buf()).unwrap();
-        local_repository.remote(
-            remote_name,
-            &format!("file://{}/.git", remote_dir.to_path_buf().to_str().unwrap()),
-        );
+        git2::Repository::init(&remote_dir).unwrap();
+        let local_repository = git2::Repository::init(&local_dir).unwrap();
+        local_repository
+            .remote(
+                remote_name,
+                &format!("file://{}/.git", remote_dir.to_path_buf().to_str().unwrap()),
+            )
+            .unwrap();
         let config = serde_json::to_string(&Config {
             repositories: vec![GitRepository {
                 local_path: local_dir.to_path_buf(),
-                fetch_branches: vec!["main".to_string()],
-                remote: "origin".to_string(),
+                fetch_branches: Some(vec!["main".to_string()]),
+                remote: Some("origin".to_string()),
+                ..Default::default()
             }],
+            ..Default::default()
         })
         .unwrap();
         let config_file = temp.child("config.json");
@@ -131,4 +965,950 @@ mod tests {
         let assert = cmd.arg("--config-file").arg(config_file.path()).assert();
         assert.code(0);
     }
+
+    #[test]
+    fn test_clone_missing_repository() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let mut cmd = command(&temp);
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                fetch_branches: Some(vec!["main".to_string()]),
+                remote: Some("upstream".to_string()),
+                url: Some(file_url(&remote_dir)),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        let assert = cmd.arg("--config-file").arg(config_file.path()).assert();
+        assert.code(0);
+        let local_repository = git2::Repository::open(&local_dir).unwrap();
+        local_repository.find_remote("upstream").unwrap();
+    }
+
+    #[test]
+    fn test_fetch_interval() {
+        let mut repository = GitRepository::default();
+        assert_eq!(repository.fetch_interval(None), None);
+        assert_eq!(
+            repository.fetch_interval(Some(60)),
+            Some(Duration::from_secs(60))
+        );
+        repository.fetch_interval = Some(5);
+        assert_eq!(
+            repository.fetch_interval(Some(60)),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_default_config_file() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let home = temp.child("home");
+        let xdg = temp.child("xdg");
+        home.child(".config/git-auto-fetch/config.json")
+            .write_str("{}")
+            .unwrap();
+        let var = |explicit: Option<&str>| {
+            let (home, xdg) = (home.to_path_buf(), xdg.to_path_buf());
+            let explicit = explicit.map(OsString::from);
+            move |name: &str| match name {
+                "GIT_AUTO_FETCH_CONFIG" => explicit.clone(),
+                "XDG_CONFIG_HOME" => Some(xdg.clone().into_os_string()),
+                "HOME" => Some(home.clone().into_os_string()),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            default_config_file(var(None)).unwrap(),
+            home.path().join(".config/git-auto-fetch/config.json")
+        );
+        xdg.child("git-auto-fetch/config.yaml")
+            .write_str("repositories: []")
+            .unwrap();
+        assert_eq!(
+            default_config_file(var(None)).unwrap(),
+            xdg.path().join("git-auto-fetch/config.yaml")
+        );
+        assert_eq!(
+            default_config_file(var(Some("/etc/fetch.toml"))).unwrap(),
+            PathBuf::from("/etc/fetch.toml")
+        );
+        assert!(default_config_file(|_| None).is_err());
+    }
+
+    #[test]
+    fn test_layered_config() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("shared/base.toml")
+            .write_str(
+                "fetch_interval = 600\nremote = \"upstream\"\n\n[[repositories]]\nlocal_path = \"/repos/a\"\nfetch_branches = [\"main\"]\n\n[[post_fetch]]\ncommand = \"make\"\n",
+            )
+            .unwrap();
+        temp.child("config.json")
+            .write_str(r#"{"include": ["shared/base.toml"], "fetch_interval": 60, "repositories": [{"local_path": "/repos/b"}]}"#)
+            .unwrap();
+        temp.child("config.d/20-late.json")
+            .write_str(r#"{"max_concurrency": 2, "post_fetch": [{"command": "make docs"}]}"#)
+            .unwrap();
+        temp.child("config.d/10-user.yaml")
+            .write_str("max_concurrency: 8\nrepositories:\n  - local_path: /repos/a\n    fetch_branches: [dev]\n")
+            .unwrap();
+        temp.child("config.d/notes.txt")
+            .write_str("ignored")
+            .unwrap();
+        let config = load_config(temp.path().join("config.json")).unwrap();
+        assert_eq!(config.fetch_interval, Some(60));
+        assert_eq!(config.max_concurrency, Some(2));
+        assert_eq!(config.remote.as_deref(), Some("upstream"));
+        assert_eq!(
+            config.post_fetch,
+            Some(vec![PostFetch {
+                command: "make docs".to_string(),
+                timeout: None,
+            }])
+        );
+        let repositories: Vec<_> = config
+            .repositories
+            .iter()
+            .map(|repository| {
+                (
+                    repository.local_path.clone(),
+                    repository.fetch_branches.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            repositories,
+            vec![
+                (PathBuf::from("/repos/a"), Some(vec!["dev".to_string()])),
+                (PathBuf::from("/repos/b"), None),
+            ]
+        );
+
+        temp.child("config.d/30-quiet.toml")
+            .write_str("post_fetch = []\n")
+            .unwrap();
+        let config = load_config(temp.path().join("config.json")).unwrap();
+        assert_eq!(config.post_fetch, Some(Vec::new()));
+
+        temp.child("shared/base.toml")
+            .write_str("include = [\"../config.json\"]\n")
+            .unwrap();
+        assert!(load_config(temp.path().join("config.json")).is_err());
+    }
+
+    #[test]
+    fn test_merge_replaces_same_repository() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("repos/a").create_dir_all().unwrap();
+        let repository = |path: PathBuf, remote: &str| GitRepository {
+            local_path: path,
+            remote: Some(remote.to_string()),
+            ..Default::default()
+        };
+        let mut config = Config::default();
+        config.merge(Config {
+            repositories: vec![
+                repository(temp.path().join("repos/a"), "first"),
+                repository(temp.path().join("repos/b"), "other"),
+                repository(temp.path().join("repos/../repos/a"), "second"),
+            ],
+            ..Default::default()
+        });
+        config.merge(Config {
+            repositories: vec![
+                repository(temp.path().join("repos/b"), "third"),
+                repository(temp.path().join("repos/c"), "new"),
+            ],
+            ..Default::default()
+        });
+        let remotes: Vec<_> = config
+            .repositories
+            .iter()
+            .map(|repository| repository.remote.as_deref().unwrap())
+            .collect();
+        assert_eq!(remotes, vec!["second", "third", "new"]);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let mut cmd = command(&temp);
+        let config_file = temp.child("config.json");
+        config_file.write_str("{\"repositories\": 42}").unwrap();
+        let assert = cmd.arg("--config-file").arg(config_file.path()).assert();
+        assert.code(EXIT_CONFIG_INVALID);
+    }
+
+    #[test]
+    fn test_failed_repository_does_not_stop_others() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let mut cmd = command(&temp);
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        git2::Repository::init(&remote_dir).unwrap();
+        git2::Repository::init(&local_dir)
+            .unwrap()
+            .remote("origin", &file_url(&remote_dir))
+            .unwrap();
+        let config = Config {
+            repositories: vec![
+                GitRepository {
+                    local_path: temp.child("missing").to_path_buf(),
+                    fetch_branches: Some(vec!["main".to_string()]),
+                    remote: Some("origin".to_string()),
+                    ..Default::default()
+                },
+                GitRepository {
+                    local_path: local_dir.to_path_buf(),
+                    fetch_branches: Some(vec!["main".to_string()]),
+                    remote: Some("origin".to_string()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        let assert = cmd.arg("--config-file").arg(config_file.path()).assert();
+        assert
+            .code(EXIT_FETCH_FAILED)
+            .stdout(predicates::str::contains("Fetched 1 of 2 repositories"));
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                url: Some(file_url(&remote_dir)),
+                fast_forward: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        let local_repository = git2::Repository::open(&local_dir).unwrap();
+        let head = local_repository.head().unwrap();
+        let (branch, old_commit) = (head.shorthand().unwrap(), head.target().unwrap());
+        let new_commit = commit(&remote_repository, "second");
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains(format!(
+                "branch {} updated {}..{}",
+                branch, old_commit, new_commit
+            )))
+            .stdout(predicates::str::contains(format!(
+                "refs/remotes/origin/{} updated (+1)",
+                branch
+            )));
+        assert_eq!(local_repository.head().unwrap().target(), Some(new_commit));
+    }
+
+    #[test]
+    fn test_default_remote_and_branches() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        let head = commit(&remote_repository, "initial");
+        git2::Repository::init(&local_dir)
+            .unwrap()
+            .remote("upstream", &file_url(&remote_dir))
+            .unwrap();
+        let config_file = temp.child("config.json");
+        config_file
+            .write_str(&format!(
+                "{{\"repositories\": [{{\"local_path\": {:?}}}]}}",
+                local_dir.to_str().unwrap()
+            ))
+            .unwrap();
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        let local_repository = git2::Repository::open(&local_dir).unwrap();
+        let branch = remote_repository
+            .head()
+            .unwrap()
+            .shorthand()
+            .unwrap()
+            .to_string();
+        let tracking = local_repository
+            .find_reference(&format!("refs/remotes/upstream/{}", branch))
+            .unwrap();
+        assert_eq!(tracking.target(), Some(head));
+    }
+
+    #[test]
+    fn test_inherit_defaults() {
+        let config = Config {
+            remote: Some("upstream".to_string()),
+            fetch_branches: Some(vec!["main".to_string()]),
+            ..Default::default()
+        };
+        let mut repository = GitRepository {
+            fetch_branches: Some(Vec::new()),
+            ..Default::default()
+        };
+        repository.inherit(&config);
+        assert_eq!(repository.remote.as_deref(), Some("upstream"));
+        assert_eq!(repository.fetch_branches, Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_fetch_all_remotes() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        let head = commit(&remote_repository, "initial");
+        let branch = remote_repository
+            .head()
+            .unwrap()
+            .shorthand()
+            .unwrap()
+            .to_string();
+        let local_repository = git2::Repository::init(&local_dir).unwrap();
+        let url = file_url(&remote_dir);
+        local_repository.remote("origin", &url).unwrap();
+        local_repository.remote("upstream", &url).unwrap();
+        local_repository
+            .remote(
+                "broken",
+                &format!("file://{}", temp.child("missing").to_str().unwrap()),
+            )
+            .unwrap();
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                remotes: vec![RemoteSpec {
+                    name: "*".to_string(),
+                    fetch_branches: None,
+                }],
+                ..Default::default()
+            }],
+            retry: Some(RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(EXIT_FETCH_FAILED)
+            .stdout(predicates::str::contains("remote origin ok"))
+            .stdout(predicates::str::contains("remote upstream ok"))
+            .stdout(predicates::str::contains("remote broken:"));
+        for remote in &["origin", "upstream"] {
+            let tracking = local_repository
+                .find_reference(&format!("refs/remotes/{}/{}", remote, branch))
+                .unwrap();
+            assert_eq!(tracking.target(), Some(head));
+        }
+    }
+
+    #[test]
+    fn test_prune() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        let head = commit(&remote_repository, "initial");
+        let head = remote_repository.find_commit(head).unwrap();
+        remote_repository.branch("feature", &head, false).unwrap();
+        remote_repository
+            .tag_lightweight("v1", head.as_object(), false)
+            .unwrap();
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                url: Some(file_url(&remote_dir)),
+                prune: true,
+                prune_tags: true,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        let local_repository = git2::Repository::open(&local_dir).unwrap();
+        local_repository
+            .find_reference("refs/remotes/origin/feature")
+            .unwrap();
+        local_repository.find_reference("refs/tags/v1").unwrap();
+        remote_repository
+            .find_branch("feature", git2::BranchType::Local)
+            .unwrap()
+            .delete()
+            .unwrap();
+        remote_repository
+            .find_reference("refs/tags/v1")
+            .unwrap()
+            .delete()
+            .unwrap();
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains(
+                "refs/remotes/origin/feature deleted",
+            ))
+            .stdout(predicates::str::contains("refs/tags/v1 deleted"));
+        assert!(local_repository
+            .find_reference("refs/remotes/origin/feature")
+            .is_err());
+        assert!(local_repository.find_reference("refs/tags/v1").is_err());
+    }
+
+    #[test]
+    fn test_skip_unchanged() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                url: Some(file_url(&remote_dir)),
+                ..Default::default()
+            }],
+            skip_unchanged: Some(true),
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains("remote origin unchanged"));
+        commit(&remote_repository, "second");
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains("remote origin ok"))
+            .stdout(predicates::str::contains("updated (+1)"));
+    }
+
+    #[test]
+    fn test_shallow_fetch() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        commit(&remote_repository, "second");
+        let mut repository = GitRepository {
+            local_path: local_dir.to_path_buf(),
+            url: Some(file_url(&remote_dir)),
+            depth: Some(1),
+            filter: Some("blob:none".to_string()),
+            ..Default::default()
+        };
+        let write = |repository: &GitRepository| {
+            write_config(
+                &temp,
+                &Config {
+                    repositories: vec![repository.clone()],
+                    ..Default::default()
+                },
+            )
+        };
+        let config_file = write(&repository);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        let local_repository = git2::Repository::open(&local_dir).unwrap();
+        assert!(local_repository.is_shallow());
+        let new_commit = commit(&remote_repository, "third");
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains("refs/remotes/origin/"));
+        assert!(local_repository.find_commit(new_commit).is_ok());
+
+        repository.depth = None;
+        repository.unshallow = true;
+        write(&repository);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        assert!(!git2::Repository::open(&local_dir).unwrap().is_shallow());
+    }
+
+    #[test]
+    fn test_shallow_clone_into_relative_path() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: PathBuf::from("repos/shallow/a"),
+                url: Some(file_url(&remote_dir)),
+                depth: Some(1),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        for _ in 0..2 {
+            command(&temp)
+                .current_dir(temp.path())
+                .arg("--config-file")
+                .arg(config_file.path())
+                .assert()
+                .code(0);
+        }
+        let local_repository = git2::Repository::open(temp.child("repos/shallow/a")).unwrap();
+        assert!(local_repository.is_shallow());
+        temp.child("repos/shallow/repos")
+            .assert(predicates::path::missing());
+    }
+
+    #[test]
+    fn test_git_backend() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                url: Some(file_url(&remote_dir)),
+                skip_unchanged: Some(true),
+                ..Default::default()
+            }],
+            backend: Some(Backend::Git),
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains("remote origin unchanged"));
+        commit(&remote_repository, "second");
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains("updated (+1)"));
+    }
+
+    #[test]
+    fn test_post_fetch() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let log = temp.child("post_fetch.log");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        git2::Repository::clone(&file_url(&remote_dir), &local_dir).unwrap();
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                post_fetch: vec![PostFetch {
+                    command: "exit 4".to_string(),
+                    timeout: None,
+                }],
+                ..Default::default()
+            }],
+            post_fetch: Some(vec![PostFetch {
+                command: format!("cat >> {}", log.path().display()),
+                timeout: Some(10),
+            }]),
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(0);
+        log.assert(predicates::path::missing());
+        let new_commit = commit(&remote_repository, "second");
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .assert()
+            .code(EXIT_OK)
+            .stdout(predicates::str::contains(
+                "post_fetch \"exit 4\": Exited(4)",
+            ));
+        log.assert(predicates::str::contains(format!(
+            "{} refs/remotes/origin/",
+            new_commit
+        )));
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("status")
+            .assert()
+            .code(EXIT_OK)
+            .stdout(predicates::str::contains("last success: never").not())
+            .stdout(predicates::str::contains(
+                "last post_fetch error: \"exit 4\": Exited(4)",
+            ));
+    }
+
+    #[test]
+    fn test_tag_policy() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        let head = commit(&remote_repository, "initial");
+        let head = remote_repository.find_object(head, None).unwrap();
+        remote_repository
+            .tag_lightweight("v1", &head, false)
+            .unwrap();
+        let local_repository = git2::Repository::init(&local_dir).unwrap();
+        local_repository
+            .remote("origin", &file_url(&remote_dir))
+            .unwrap();
+        for (policy, expect_tag) in &[(TagPolicy::None, false), (TagPolicy::All, true)] {
+            let config = Config {
+                repositories: vec![GitRepository {
+                    local_path: local_dir.to_path_buf(),
+                    tags: Some(*policy),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            let config_file = write_config(&temp, &config);
+            command(&temp)
+                .arg("--config-file")
+                .arg(config_file.path())
+                .assert()
+                .code(0);
+            assert_eq!(
+                local_repository.find_reference("refs/tags/v1").is_ok(),
+                *expect_tag
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_report() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        let head = commit(&remote_repository, "initial");
+        git2::Repository::init(&local_dir)
+            .unwrap()
+            .remote("origin", &file_url(&remote_dir))
+            .unwrap();
+        let config = Config {
+            repositories: vec![GitRepository {
+                local_path: local_dir.to_path_buf(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        let output = command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("--report")
+            .arg("json")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let record = &report[0];
+        assert_eq!(record["path"], local_dir.to_str().unwrap());
+        assert_eq!(record["status"], "ok");
+        assert_eq!(record["remotes"][0]["name"], "origin");
+        assert_eq!(
+            record["remotes"][0]["refs_updated"][0]["new"],
+            head.to_string()
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        git2::Repository::init(&local_dir)
+            .unwrap()
+            .remote("origin", "file:///nonexistent")
+            .unwrap();
+        let config = Config {
+            repositories: vec![
+                GitRepository {
+                    local_path: local_dir.to_path_buf(),
+                    fetch_branches: Some(vec!["main".to_string(), "bad..name".to_string()]),
+                    ..Default::default()
+                },
+                GitRepository {
+                    local_path: local_dir.to_path_buf(),
+                    remote: Some("upstream".to_string()),
+                    ..Default::default()
+                },
+                GitRepository {
+                    local_path: temp.child("missing").to_path_buf(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("check")
+            .assert()
+            .code(EXIT_CONFIG_INVALID)
+            .stdout(predicates::str::contains(
+                "repositories[0].fetch_branches[1]",
+            ))
+            .stdout(predicates::str::contains("repositories[1].remote"))
+            .stdout(predicates::str::contains("repositories[2].local_path"))
+            .stdout(predicates::str::contains("3 problems"));
+
+        let fragment = temp.child("config.d/extra.json");
+        fragment
+            .write_str(&format!(
+                "{{\"repositories\": [{{\"local_path\": {:?}, \"depth\": 0}}]}}",
+                temp.child("gone").to_str().unwrap()
+            ))
+            .unwrap();
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("check")
+            .assert()
+            .code(EXIT_CONFIG_INVALID)
+            .stdout(predicates::str::contains(format!(
+                "{}: repositories[0].depth",
+                fragment.display()
+            )))
+            .stdout(predicates::str::contains(format!(
+                "{}: repositories[0].local_path",
+                fragment.display()
+            )))
+            .stdout(predicates::str::contains("5 problems"));
+
+        fragment.write_str("{\"repositories\": 42}").unwrap();
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("check")
+            .assert()
+            .code(EXIT_CONFIG_INVALID)
+            .stdout(predicates::str::contains(format!(
+                "{}: invalid type",
+                fragment.display()
+            )));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let url = file_url(&remote_dir);
+        git2::Repository::init(&local_dir)
+            .unwrap()
+            .remote("origin", &url)
+            .unwrap();
+        let config = Config {
+            repositories: vec![
+                GitRepository {
+                    local_path: local_dir.to_path_buf(),
+                    ..Default::default()
+                },
+                GitRepository {
+                    local_path: temp.child("new").to_path_buf(),
+                    url: Some(url.clone()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("--dry-run")
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains(format!(
+                "remote origin ({})",
+                url
+            )))
+            .stdout(predicates::str::contains(
+                "+refs/heads/*:refs/remotes/origin/*",
+            ))
+            .stdout(predicates::str::contains(format!(
+                "clone {} as remote origin",
+                url
+            )));
+        let local_repository = git2::Repository::open(&local_dir).unwrap();
+        assert!(local_repository.references().unwrap().next().is_none());
+        assert!(!temp.child("new").exists());
+    }
+
+    #[test]
+    fn test_add_list_remove() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let local_dir = temp.child("local");
+        git2::Repository::init(&local_dir).unwrap();
+        let config_file = temp.child("config.json");
+        config_file
+            .write_str("{\n  \"repositories\": []\n}\n")
+            .unwrap();
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .args(["--dry-run", "add", local_dir.to_str().unwrap()])
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains(local_dir.to_str().unwrap()));
+        config_file.assert("{\n  \"repositories\": []\n}\n");
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .args(["add", local_dir.to_str().unwrap(), "--remote", "upstream"])
+            .args(["--branch", "main", "--branch", "dev"])
+            .assert()
+            .code(0);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("list")
+            .assert()
+            .code(0)
+            .stdout(format!("{}\tupstream\tmain,dev\n", local_dir.display()));
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .args(["remove", local_dir.to_str().unwrap()])
+            .assert()
+            .code(0);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .args(["remove", local_dir.to_str().unwrap()])
+            .assert()
+            .code(EXIT_USAGE);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("list")
+            .assert()
+            .code(0)
+            .stdout("");
+    }
+
+    #[test]
+    fn test_status_from_state() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let remote_dir = temp.child("remote");
+        let remote_repository = git2::Repository::init(&remote_dir).unwrap();
+        commit(&remote_repository, "initial");
+        let url = file_url(&remote_dir);
+        let config = Config {
+            repositories: vec![
+                GitRepository {
+                    local_path: temp.child("good").to_path_buf(),
+                    url: Some(url),
+                    ..Default::default()
+                },
+                GitRepository {
+                    local_path: temp.child("bad").to_path_buf(),
+                    ..Default::default()
+                },
+            ],
+            retry: Some(RetryPolicy {
+                max_attempts: 1,
+                ..Default::default()
+            }),
+            state_file: Some(temp.child("state.json").to_path_buf()),
+            ..Default::default()
+        };
+        let config_file = write_config(&temp, &config);
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("status")
+            .assert()
+            .code(0)
+            .stdout(predicates::str::contains("never fetched"));
+        command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("run")
+            .assert()
+            .code(EXIT_FETCH_FAILED);
+        let status = command(&temp)
+            .arg("--config-file")
+            .arg(config_file.path())
+            .arg("status")
+            .assert()
+            .code(0)
+            .get_output()
+            .stdout
+            .clone();
+        let status = String::from_utf8(status).unwrap();
+        let (good, bad) = status.split_at(status.find("/bad\n").unwrap());
+        assert!(good.contains("last success: "), "{}", status);
+        assert!(!good.contains("never"), "{}", status);
+        assert!(bad.contains("last success: never"), "{}", status);
+        assert!(bad.contains("last error:"), "{}", status);
+    }
 }
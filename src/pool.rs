@@ -0,0 +1,177 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    panic::{self, AssertUnwindSafe},
+    sync::{Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Jobs waiting for a worker, ordered by when they are due.
+struct Queue {
+    pending: BinaryHeap<Reverse<(Instant, usize)>>,
+    running: usize,
+}
+
+/// Number of workers to use when the config doesn't set a limit.
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|workers| workers.get())
+        .unwrap_or(1)
+}
+
+/// Runs `work` for every job on at most `workers` threads, the first time
+/// after the delay `start` returns for it. After a job finishes it is queued
+/// again if `interval` returns a delay for it, which gets `None` instead of
+/// the result if the work panicked. Returns once no job is left, with the
+/// last result of each job in input order; a job whose work panicked yields
+/// `Err` with the panic payload.
+pub fn run<J, R, S, W, I>(
+    jobs: &[J],
+    workers: usize,
+    start: S,
+    work: W,
+    interval: I,
+) -> Vec<thread::Result<R>>
+where
+    J: Sync,
+    R: Send,
+    S: Fn(&J) -> Duration,
+    W: Fn(&J) -> R + Sync,
+    I: Fn(&J, Option<&R>) -> Option<Duration> + Sync,
+{
+    let now = Instant::now();
+    let queue = Mutex::new(Queue {
+        pending: jobs
+            .iter()
+            .enumerate()
+            .map(|(index, job)| Reverse((now + start(job), index)))
+            .collect(),
+        running: 0,
+    });
+    let wakeup = Condvar::new();
+    let results: Mutex<Vec<Option<thread::Result<R>>>> =
+        Mutex::new(jobs.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, jobs.len().max(1)) {
+            scope.spawn(|| loop {
+                let index = {
+                    let mut queue = queue.lock().unwrap();
+                    loop {
+                        let now = Instant::now();
+                        match queue.pending.peek() {
+                            None if queue.running == 0 => {
+                                wakeup.notify_all();
+                                return;
+                            }
+                            None => queue = wakeup.wait(queue).unwrap(),
+                            Some(Reverse((due, _))) if *due > now => {
+                                let timeout = *due - now;
+                                queue = wakeup.wait_timeout(queue, timeout).unwrap().0;
+                            }
+                            Some(_) => {
+                                let Reverse((_, index)) = queue.pending.pop().unwrap();
+                                queue.running += 1;
+                                break index;
+                            }
+                        }
+                    }
+                };
+                let job = &jobs[index];
+                let result = panic::catch_unwind(AssertUnwindSafe(|| work(job)));
+                let next = interval(job, result.as_ref().ok());
+                results.lock().unwrap()[index] = Some(result);
+                let mut queue = queue.lock().unwrap();
+                queue.running -= 1;
+                if let Some(next) = next {
+                    queue.pending.push(Reverse((Instant::now() + next, index)));
+                }
+                wakeup.notify_all();
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every job runs at least once"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_concurrency_limit() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let jobs: Vec<usize> = (0..16).collect();
+        let results = run(
+            &jobs,
+            3,
+            |_| Duration::ZERO,
+            |job| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                job * 2
+            },
+            |_, _| None,
+        );
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+        let results: Vec<usize> = results.into_iter().map(Result::unwrap).collect();
+        assert_eq!(results, jobs.iter().map(|job| job * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rescheduling() {
+        let runs = AtomicUsize::new(0);
+        let results = run(
+            &[()],
+            2,
+            |_| Duration::ZERO,
+            |_| runs.fetch_add(1, Ordering::SeqCst) + 1,
+            |_, count| (*count.unwrap() < 3).then(|| Duration::from_millis(1)),
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert_eq!(*results[0].as_ref().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_rescheduling_after_panic() {
+        let runs = AtomicUsize::new(0);
+        let results = run(
+            &[()],
+            1,
+            |_| Duration::ZERO,
+            |_| {
+                let count = runs.fetch_add(1, Ordering::SeqCst) + 1;
+                if count == 1 {
+                    panic!("first run fails");
+                }
+                count
+            },
+            |_, count| (count.copied() != Some(2)).then(|| Duration::from_millis(1)),
+        );
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        assert_eq!(*results[0].as_ref().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_start_delay() {
+        let start = Instant::now();
+        let results = run(
+            &[Duration::from_millis(30), Duration::ZERO],
+            2,
+            |delay| *delay,
+            |_| start.elapsed(),
+            |_, _| None,
+        );
+        let elapsed: Vec<Duration> = results.into_iter().map(Result::unwrap).collect();
+        assert!(elapsed[0] >= Duration::from_millis(30));
+        assert!(elapsed[1] < Duration::from_millis(30));
+    }
+}
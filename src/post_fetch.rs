@@ -0,0 +1,213 @@
+use crate::{fetch::RefUpdate, GitRepository};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    os::unix::process::CommandExt,
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Seconds a post-fetch command may run when it sets no timeout.
+const DEFAULT_TIMEOUT: u64 = 300;
+
+/// How often a running command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A shell command run in the repository after a fetch changed refs.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PostFetch {
+    /// Passed to `sh -c`.
+    pub command: String,
+    /// Seconds after which the command is killed, 300 by default.
+    pub timeout: Option<u64>,
+}
+
+/// How a post-fetch command ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostFetchStatus {
+    /// Exited with this code.
+    Exited(i32),
+    /// Terminated by a signal.
+    Signaled,
+    /// Killed after running for longer than its timeout.
+    TimedOut,
+    /// Could not be started.
+    Failed(String),
+}
+
+/// What running a post-fetch command did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostFetchOutcome {
+    pub command: String,
+    pub status: PostFetchStatus,
+    pub duration: Duration,
+}
+
+impl PostFetchOutcome {
+    pub fn is_success(&self) -> bool {
+        self.status == PostFetchStatus::Exited(0)
+    }
+}
+
+/// The lines fed to a command's stdin: `<old> <new> <ref>` per changed ref,
+/// like git's `post-receive` hook.
+fn stdin_lines(updates: &[&RefUpdate]) -> String {
+    updates
+        .iter()
+        .map(|update| format!("{} {} {}\n", update.old, update.new, update.name))
+        .collect()
+}
+
+/// Kills `child` and everything it started, which runs in a process group of
+/// its own.
+fn kill_group(child: &std::process::Child) -> io::Result<()> {
+    // SAFETY: killpg only sends a signal; the group id is the pid of a child
+    // that hasn't been waited for, so it can't have been reused.
+    if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Waits for `child` to exit, killing its process group once `timeout` has
+/// passed.
+fn wait(child: &mut std::process::Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if start.elapsed() >= timeout {
+            kill_group(child)?;
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl PostFetch {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+
+    /// Runs the command for `repository`, whose refs `updates` changed. The
+    /// changed refs are passed on stdin and in `GIT_AUTO_FETCH_CHANGED_REFS`;
+    /// the command's output goes to stderr so stdout stays free for the
+    /// report.
+    pub fn run(&self, repository: &GitRepository, updates: &[&RefUpdate]) -> PostFetchOutcome {
+        let start = Instant::now();
+        let status = match self.spawn(repository, updates) {
+            Ok(status) => status,
+            Err(error) => PostFetchStatus::Failed(error.to_string()),
+        };
+        match &status {
+            PostFetchStatus::Exited(0) => debug!(
+                "Post-fetch command {:?} of {:?} succeeded",
+                self.command, repository.local_path
+            ),
+            status => warn!(
+                "Post-fetch command {:?} of {:?} failed: {:?}",
+                self.command, repository.local_path, status
+            ),
+        }
+        PostFetchOutcome {
+            command: self.command.clone(),
+            status,
+            duration: start.elapsed(),
+        }
+    }
+
+    fn spawn(
+        &self,
+        repository: &GitRepository,
+        updates: &[&RefUpdate],
+    ) -> io::Result<PostFetchStatus> {
+        let names: Vec<&str> = updates.iter().map(|update| update.name.as_str()).collect();
+        info!(
+            "Running post-fetch command {:?} in {:?}",
+            self.command, repository.local_path
+        );
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .current_dir(&repository.local_path)
+            .env("GIT_AUTO_FETCH_REPOSITORY", &repository.local_path)
+            .env("GIT_AUTO_FETCH_CHANGED_REFS", names.join(" "))
+            .env("GIT_AUTO_FETCH_CHANGED_REF_COUNT", names.len().to_string())
+            .stdin(Stdio::piped())
+            .stdout(io::stderr())
+            .stderr(io::stderr())
+            // Lets a timeout kill what the command started, not just `sh`.
+            .process_group(0)
+            .spawn()?;
+        // Write from another thread so a command that doesn't read its input
+        // can't block us past the timeout.
+        if let Some(mut stdin) = child.stdin.take() {
+            let input = stdin_lines(updates);
+            thread::spawn(move || {
+                // The command may exit without reading; that's fine.
+                let _ = stdin.write_all(input.as_bytes());
+            });
+        }
+        Ok(match wait(&mut child, self.timeout())? {
+            Some(status) => match status.code() {
+                Some(code) => PostFetchStatus::Exited(code),
+                None => PostFetchStatus::Signaled,
+            },
+            None => PostFetchStatus::TimedOut,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::RefChange;
+    use git2::Oid;
+
+    #[test]
+    fn test_run() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let repository = GitRepository {
+            local_path: temp.path().to_path_buf(),
+            ..Default::default()
+        };
+        let update = RefUpdate {
+            name: "refs/remotes/origin/main".to_string(),
+            old: Oid::zero(),
+            new: Oid::from_str("1234").unwrap(),
+            change: RefChange::Created,
+        };
+        let hook = |command: &str, timeout| PostFetch {
+            command: command.to_string(),
+            timeout,
+        };
+        let outcome = hook(
+            "test \"$GIT_AUTO_FETCH_CHANGED_REFS\" = refs/remotes/origin/main && grep -q ' refs/remotes/origin/main$'",
+            None,
+        )
+        .run(&repository, &[&update]);
+        assert_eq!(outcome.status, PostFetchStatus::Exited(0));
+        assert_eq!(
+            hook("exit 3", None).run(&repository, &[&update]).status,
+            PostFetchStatus::Exited(3)
+        );
+        assert_eq!(
+            hook("sleep 5", Some(0)).run(&repository, &[&update]).status,
+            PostFetchStatus::TimedOut
+        );
+
+        // Whatever the command started in the background dies with it.
+        assert_eq!(
+            hook("(sleep 2; touch survived) & wait", Some(1))
+                .run(&repository, &[&update])
+                .status,
+            PostFetchStatus::TimedOut
+        );
+        thread::sleep(Duration::from_secs(2));
+        assert!(!temp.path().join("survived").exists());
+    }
+}
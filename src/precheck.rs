@@ -0,0 +1,180 @@
+use git2::Oid;
+use std::collections::HashMap;
+
+/// A fetch refspec reduced to the full ref names it reads and writes.
+struct Mapping {
+    src: String,
+    dst: Option<String>,
+}
+
+/// Expands a short name such as `main` the way `git fetch` does for the
+/// source of a refspec.
+fn full_name(name: &str) -> String {
+    if name.starts_with("refs/") || name == "HEAD" {
+        name.to_string()
+    } else {
+        format!("refs/heads/{}", name)
+    }
+}
+
+fn parse(refspec: &str) -> Mapping {
+    let spec = refspec.strip_prefix('+').unwrap_or(refspec);
+    let (src, dst) = match spec.split_once(':') {
+        Some((src, dst)) => (src, Some(dst).filter(|dst| !dst.is_empty())),
+        None => (spec, None),
+    };
+    Mapping {
+        src: full_name(src),
+        dst: dst.map(full_name),
+    }
+}
+
+/// The part of `name` matched by the `*` in `pattern`, or `""` if `pattern`
+/// has no `*` and equals `name`.
+fn capture<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => name.strip_prefix(prefix)?.strip_suffix(suffix),
+        None => (pattern == name).then_some(""),
+    }
+}
+
+impl Mapping {
+    /// Where the remote ref `name` is stored locally under this mapping.
+    fn destination(&self, name: &str) -> Option<String> {
+        let captured = capture(&self.src, name)?;
+        Some(self.dst.as_ref()?.replacen('*', captured, 1))
+    }
+}
+
+/// Whether fetching `refspecs` would leave the local refs as they are, given
+/// the refs the remote advertises. `configured` are the remote's own
+/// refspecs, which decide where refs fetched without a destination are
+/// stored; with `prune`, a local ref whose remote ref is gone is a change
+/// too. Anything that can't be compared counts as changed.
+pub fn is_unchanged(
+    advertised: &[(String, Oid)],
+    local: &HashMap<String, Oid>,
+    refspecs: &[String],
+    configured: &[String],
+    prune: bool,
+) -> bool {
+    let requested = if refspecs.is_empty() {
+        configured
+    } else {
+        refspecs
+    };
+    let mappings: Vec<Mapping> = requested.iter().map(|refspec| parse(refspec)).collect();
+    let configured: Vec<Mapping> = configured.iter().map(|refspec| parse(refspec)).collect();
+    let mut expected = HashMap::new();
+    for (name, oid) in advertised {
+        if name.ends_with("^{}") {
+            continue;
+        }
+        for mapping in &mappings {
+            if capture(&mapping.src, name).is_none() {
+                continue;
+            }
+            let destination = match &mapping.dst {
+                Some(_) => mapping.destination(name),
+                None => configured
+                    .iter()
+                    .find_map(|mapping| mapping.destination(name)),
+            };
+            match destination {
+                Some(destination) => {
+                    expected.insert(destination, *oid);
+                }
+                None => return false,
+            }
+        }
+    }
+    // A ref asked for by name that the remote doesn't have fails the fetch.
+    if mappings
+        .iter()
+        .filter(|mapping| !mapping.src.contains('*'))
+        .any(|mapping| !advertised.iter().any(|(name, _)| *name == mapping.src))
+    {
+        return false;
+    }
+    if expected
+        .iter()
+        .any(|(destination, oid)| local.get(destination) != Some(oid))
+    {
+        return false;
+    }
+    if prune {
+        let tracked = |name: &str| {
+            mappings
+                .iter()
+                .filter_map(|mapping| mapping.dst.as_deref())
+                .any(|dst| capture(dst, name).is_some())
+        };
+        if local
+            .keys()
+            .any(|name| tracked(name) && !expected.contains_key(name))
+        {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_unchanged() {
+        let a = Oid::from_str("aaaa").unwrap();
+        let b = Oid::from_str("bbbb").unwrap();
+        let advertised = vec![
+            ("HEAD".to_string(), a),
+            ("refs/heads/main".to_string(), a),
+            ("refs/heads/dev".to_string(), b),
+            ("refs/tags/v1^{}".to_string(), b),
+        ];
+        let configured = vec!["+refs/heads/*:refs/remotes/origin/*".to_string()];
+        let mut local: HashMap<String, Oid> = vec![
+            ("refs/remotes/origin/main".to_string(), a),
+            ("refs/remotes/origin/dev".to_string(), b),
+        ]
+        .into_iter()
+        .collect();
+        assert!(is_unchanged(&advertised, &local, &[], &configured, false));
+        assert!(is_unchanged(
+            &advertised,
+            &local,
+            &["main".to_string()],
+            &configured,
+            false
+        ));
+        assert!(!is_unchanged(
+            &advertised,
+            &local,
+            &["missing".to_string()],
+            &configured,
+            false
+        ));
+
+        local.insert("refs/remotes/origin/gone".to_string(), a);
+        assert!(is_unchanged(&advertised, &local, &[], &configured, false));
+        assert!(!is_unchanged(&advertised, &local, &[], &configured, true));
+
+        local.insert("refs/remotes/origin/dev".to_string(), a);
+        assert!(!is_unchanged(&advertised, &local, &[], &configured, false));
+        assert!(is_unchanged(
+            &advertised,
+            &local,
+            &["main".to_string()],
+            &configured,
+            false
+        ));
+        assert!(!is_unchanged(
+            &advertised,
+            &local,
+            &["main".to_string()],
+            &[],
+            false
+        ));
+    }
+}
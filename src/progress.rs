@@ -0,0 +1,294 @@
+use git2::RemoteCallbacks;
+use log::{debug, info, Record};
+use log4rs::{
+    append::{console::Target, Append},
+    encode::{pattern::PatternEncoder, writer::simple::SimpleWriter, Encode},
+};
+use serde::Serialize;
+use std::{
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How often a running transfer is logged when there is no terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the live display on a terminal is redrawn.
+const DRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Totals of a finished transfer.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct TransferStats {
+    pub objects: usize,
+    pub bytes: usize,
+}
+
+impl std::ops::AddAssign for TransferStats {
+    fn add_assign(&mut self, other: Self) {
+        self.objects += other.objects;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Formats a byte count with a binary unit, e.g. `1.5 MiB`.
+pub fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+struct Transfer {
+    label: String,
+    received: usize,
+    total: usize,
+    indexed: usize,
+    bytes: usize,
+    message: String,
+    last_log: Instant,
+}
+
+impl Transfer {
+    fn line(&self) -> String {
+        format!(
+            "{}: received {}/{} objects ({}), indexed {}/{} {}",
+            self.label,
+            self.received,
+            self.total,
+            format_bytes(self.bytes),
+            self.indexed,
+            self.total,
+            self.message
+        )
+    }
+}
+
+#[derive(Default)]
+struct State {
+    transfers: Vec<Transfer>,
+    last_draw: Option<Instant>,
+}
+
+/// The lines of the live display as currently drawn at the bottom of
+/// stderr. Log output goes through `write_log`, which keeps it above them.
+static SCREEN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Removes the drawn `lines` from the terminal.
+fn clear(stderr: &mut impl Write, lines: &[String]) -> io::Result<()> {
+    if !lines.is_empty() {
+        write!(stderr, "\x1b[{}A\x1b[J", lines.len())?;
+    }
+    Ok(())
+}
+
+fn redraw(stderr: &mut impl Write, lines: &[String]) -> io::Result<()> {
+    for line in lines {
+        writeln!(stderr, "\x1b[2K{}", line)?;
+    }
+    stderr.flush()
+}
+
+/// Writes `text` to `target` above the live display, which is cleared first
+/// and drawn again below it.
+fn write_log(target: Target, text: &[u8]) -> io::Result<()> {
+    let lines = SCREEN.lock().unwrap();
+    let mut stderr = io::stderr().lock();
+    clear(&mut stderr, &lines)?;
+    stderr.flush()?;
+    match target {
+        Target::Stdout => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(text)?;
+            stdout.flush()?;
+        }
+        Target::Stderr => stderr.write_all(text)?,
+    }
+    redraw(&mut stderr, &lines)
+}
+
+/// Console appender whose lines don't get tangled with the live display.
+#[derive(Debug)]
+pub struct LogAppender {
+    target: Target,
+    encoder: PatternEncoder,
+}
+
+impl LogAppender {
+    pub fn new(target: Target) -> Self {
+        LogAppender {
+            target,
+            encoder: PatternEncoder::default(),
+        }
+    }
+}
+
+impl Append for LogAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        let mut text = SimpleWriter(Vec::new());
+        self.encoder.encode(&mut text, record)?;
+        write_log(self.target, &text.0)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+/// Shows the progress of all running transfers, either as a live display on
+/// a terminal or as periodic log lines.
+pub struct ProgressDisplay {
+    interactive: bool,
+    state: Mutex<State>,
+}
+
+impl ProgressDisplay {
+    pub fn new(interactive: bool) -> Self {
+        ProgressDisplay {
+            interactive,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Reports the progress of the fetches done with `callbacks` under
+    /// `label`.
+    pub fn watch<'a>(&'a self, callbacks: &mut RemoteCallbacks<'a>, label: &'a str) {
+        callbacks.transfer_progress(move |progress| {
+            self.update(
+                label,
+                progress.received_objects(),
+                progress.total_objects(),
+                progress.indexed_objects(),
+                progress.received_bytes(),
+            );
+            true
+        });
+        callbacks.sideband_progress(move |data| {
+            self.message(label, &String::from_utf8_lossy(data));
+            true
+        });
+    }
+
+    fn transfer<'a>(state: &'a mut State, label: &str) -> &'a mut Transfer {
+        match state
+            .transfers
+            .iter()
+            .position(|transfer| transfer.label == label)
+        {
+            Some(index) => &mut state.transfers[index],
+            None => {
+                state.transfers.push(Transfer {
+                    label: label.to_string(),
+                    received: 0,
+                    total: 0,
+                    indexed: 0,
+                    bytes: 0,
+                    message: String::new(),
+                    last_log: Instant::now(),
+                });
+                state.transfers.last_mut().unwrap()
+            }
+        }
+    }
+
+    fn update(&self, label: &str, received: usize, total: usize, indexed: usize, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        let transfer = Self::transfer(&mut state, label);
+        transfer.received = received;
+        transfer.total = total;
+        transfer.indexed = indexed;
+        transfer.bytes = bytes;
+        if !self.interactive && transfer.last_log.elapsed() >= LOG_INTERVAL {
+            transfer.last_log = Instant::now();
+            info!("{}", transfer.line());
+        }
+        self.draw(&mut state, false);
+    }
+
+    fn message(&self, label: &str, message: &str) {
+        let message = message
+            .split(['\r', '\n'])
+            .map(str::trim)
+            .rfind(|line| !line.is_empty())
+            .unwrap_or_default();
+        if message.is_empty() {
+            return;
+        }
+        debug!("{}: remote: {}", label, message);
+        let mut state = self.state.lock().unwrap();
+        Self::transfer(&mut state, label).message = message.to_string();
+        self.draw(&mut state, false);
+    }
+
+    /// Ends the transfer under `label` and returns its totals.
+    pub fn finish(&self, label: &str) -> TransferStats {
+        let mut state = self.state.lock().unwrap();
+        let stats = match state
+            .transfers
+            .iter()
+            .position(|transfer| transfer.label == label)
+        {
+            Some(index) => {
+                let transfer = state.transfers.remove(index);
+                TransferStats {
+                    objects: transfer.received,
+                    bytes: transfer.bytes,
+                }
+            }
+            None => TransferStats::default(),
+        };
+        self.draw(&mut state, true);
+        stats
+    }
+
+    fn draw(&self, state: &mut State, force: bool) {
+        if !self.interactive {
+            return;
+        }
+        let now = Instant::now();
+        if !force && matches!(state.last_draw, Some(last) if now - last < DRAW_INTERVAL) {
+            return;
+        }
+        state.last_draw = Some(now);
+        let mut lines = SCREEN.lock().unwrap();
+        let mut stderr = io::stderr().lock();
+        let _ = clear(&mut stderr, &lines);
+        *lines = state.transfers.iter().map(Transfer::line).collect();
+        let _ = redraw(&mut stderr, &lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_finish_returns_totals() {
+        let progress = ProgressDisplay::new(false);
+        progress.update("repo (origin)", 5, 10, 2, 4096);
+        progress.message("repo (origin)", "Counting objects: 100%\r\n");
+        progress.update("repo (origin)", 10, 10, 10, 8192);
+        assert_eq!(
+            progress.finish("repo (origin)"),
+            TransferStats {
+                objects: 10,
+                bytes: 8192
+            }
+        );
+        assert_eq!(progress.finish("repo (origin)"), TransferStats::default());
+    }
+}
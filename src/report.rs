@@ -0,0 +1,282 @@
+use crate::{
+    fetch::{FetchOutcome, RefChange, RefUpdate, RemoteOutcome},
+    post_fetch::{PostFetchOutcome, PostFetchStatus},
+};
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// Everything known about one repository after a run.
+#[derive(Debug)]
+pub struct RepositoryResult {
+    pub local_path: PathBuf,
+    pub duration: Duration,
+    pub result: Result<FetchOutcome, git2::Error>,
+}
+
+impl RepositoryResult {
+    /// Whether the repository was opened and every remote fetched.
+    pub fn is_success(&self) -> bool {
+        matches!(&self.result, Ok(outcome) if outcome.is_success())
+    }
+}
+
+/// Layout of the machine-readable run report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    /// A single JSON array.
+    Json,
+    /// One JSON object per line.
+    JsonLines,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(ReportFormat::Json),
+            "jsonl" | "json-lines" => Ok(ReportFormat::JsonLines),
+            _ => bail!("unknown report format {:?}, expected json or jsonl", format),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorRecord {
+    class: String,
+    code: String,
+    message: String,
+}
+
+impl From<&git2::Error> for ErrorRecord {
+    fn from(error: &git2::Error) -> Self {
+        ErrorRecord {
+            class: format!("{:?}", error.class()),
+            code: format!("{:?}", error.code()),
+            message: error.message().to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RefRecord<'a> {
+    name: &'a str,
+    old: String,
+    new: String,
+    #[serde(flatten)]
+    change: RefChange,
+}
+
+impl<'a> From<&'a RefUpdate> for RefRecord<'a> {
+    fn from(update: &'a RefUpdate) -> Self {
+        RefRecord {
+            name: &update.name,
+            old: update.old.to_string(),
+            new: update.new.to_string(),
+            change: update.change,
+        }
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "failed"
+    }
+}
+
+#[derive(Serialize)]
+struct RemoteRecord<'a> {
+    name: &'a str,
+    refspecs: &'a [String],
+    status: &'static str,
+    error: Option<ErrorRecord>,
+    objects_received: usize,
+    bytes_received: usize,
+    refs_updated: Vec<RefRecord<'a>>,
+}
+
+impl<'a> From<&'a RemoteOutcome> for RemoteRecord<'a> {
+    fn from(remote: &'a RemoteOutcome) -> Self {
+        RemoteRecord {
+            name: &remote.name,
+            refspecs: &remote.refspecs,
+            status: if remote.unchanged {
+                "unchanged"
+            } else {
+                status(remote.error.is_none())
+            },
+            error: remote.error.as_ref().map(ErrorRecord::from),
+            objects_received: remote.transfer.objects,
+            bytes_received: remote.transfer.bytes,
+            refs_updated: remote.refs.iter().map(RefRecord::from).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PostFetchRecord<'a> {
+    command: &'a str,
+    status: &'static str,
+    exit_code: Option<i32>,
+    error: Option<&'a str>,
+    duration_ms: u128,
+}
+
+impl<'a> From<&'a PostFetchOutcome> for PostFetchRecord<'a> {
+    fn from(outcome: &'a PostFetchOutcome) -> Self {
+        let (status, exit_code, error) = match &outcome.status {
+            PostFetchStatus::Exited(code) => (status(*code == 0), Some(*code), None),
+            PostFetchStatus::Signaled => ("failed", None, Some("terminated by a signal")),
+            PostFetchStatus::TimedOut => ("timed_out", None, None),
+            PostFetchStatus::Failed(error) => ("failed", None, Some(error.as_str())),
+        };
+        PostFetchRecord {
+            command: &outcome.command,
+            status,
+            exit_code,
+            error,
+            duration_ms: outcome.duration.as_millis(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RepositoryRecord<'a> {
+    path: &'a Path,
+    status: &'static str,
+    error: Option<ErrorRecord>,
+    duration_ms: u128,
+    objects_received: usize,
+    bytes_received: usize,
+    remotes: Vec<RemoteRecord<'a>>,
+    post_fetch: Vec<PostFetchRecord<'a>>,
+}
+
+impl<'a> From<&'a RepositoryResult> for RepositoryRecord<'a> {
+    fn from(result: &'a RepositoryResult) -> Self {
+        let (error, transfer, remotes, post_fetch) = match &result.result {
+            Ok(outcome) => (
+                None,
+                outcome.transfer(),
+                outcome.remotes.iter().map(RemoteRecord::from).collect(),
+                outcome
+                    .post_fetch
+                    .iter()
+                    .map(PostFetchRecord::from)
+                    .collect(),
+            ),
+            Err(error) => (
+                Some(error.into()),
+                Default::default(),
+                Vec::new(),
+                Vec::new(),
+            ),
+        };
+        RepositoryRecord {
+            path: &result.local_path,
+            status: status(result.is_success()),
+            error,
+            duration_ms: result.duration.as_millis(),
+            objects_received: transfer.objects,
+            bytes_received: transfer.bytes,
+            remotes,
+            post_fetch,
+        }
+    }
+}
+
+/// Writes one record per repository to `writer`.
+pub fn write_report(
+    results: &[RepositoryResult],
+    format: ReportFormat,
+    mut writer: impl Write,
+) -> Result<()> {
+    let records: Vec<RepositoryRecord> = results.iter().map(RepositoryRecord::from).collect();
+    match format {
+        ReportFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &records)?;
+            writeln!(writer)?;
+        }
+        ReportFormat::JsonLines => {
+            for record in &records {
+                serde_json::to_writer(&mut writer, record)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_lines() {
+        let results = vec![
+            RepositoryResult {
+                local_path: PathBuf::from("/repos/a"),
+                duration: Duration::from_millis(1500),
+                result: Ok(FetchOutcome {
+                    remotes: vec![RemoteOutcome {
+                        name: "origin".to_string(),
+                        refs: vec![RefUpdate {
+                            name: "refs/remotes/origin/main".to_string(),
+                            old: git2::Oid::zero(),
+                            new: git2::Oid::from_str("1234").unwrap(),
+                            change: RefChange::Created,
+                        }],
+                        ..Default::default()
+                    }],
+                    post_fetch: vec![PostFetchOutcome {
+                        command: "make docs".to_string(),
+                        status: PostFetchStatus::Exited(0),
+                        duration: Duration::from_millis(5),
+                    }],
+                    ..Default::default()
+                }),
+            },
+            RepositoryResult {
+                local_path: PathBuf::from("/repos/b"),
+                duration: Duration::from_millis(10),
+                result: Err(git2::Error::new(
+                    git2::ErrorCode::Auth,
+                    git2::ErrorClass::Ssh,
+                    "authentication failed",
+                )),
+            },
+        ];
+        let mut output = Vec::new();
+        write_report(&results, ReportFormat::JsonLines, &mut output).unwrap();
+        let lines: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["status"], "ok");
+        assert_eq!(lines[0]["duration_ms"], 1500);
+        assert_eq!(lines[0]["post_fetch"][0]["command"], "make docs");
+        assert_eq!(lines[0]["post_fetch"][0]["exit_code"], 0);
+        assert_eq!(
+            lines[0]["remotes"][0]["refs_updated"][0]["name"],
+            "refs/remotes/origin/main"
+        );
+        assert_eq!(
+            lines[0]["remotes"][0]["refs_updated"][0]["change"],
+            "created"
+        );
+        assert_eq!(lines[1]["status"], "failed");
+        assert_eq!(lines[1]["error"]["class"], "Ssh");
+        assert_eq!(lines[1]["error"]["code"], "Auth");
+    }
+}
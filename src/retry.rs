@@ -0,0 +1,208 @@
+use git2::{ErrorClass, ErrorCode};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    convert::TryFrom,
+    hash::{BuildHasher, Hasher},
+    thread,
+    time::Duration,
+};
+
+/// How often and how patiently to retry a fetch that failed transiently.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry in milliseconds.
+    pub initial_delay_ms: u64,
+    /// Factor the delay grows by after every retry.
+    pub backoff_factor: f64,
+    /// Fraction of the delay, between 0 and 1, that is randomly added or
+    /// subtracted so that repositories on the same server don't retry in
+    /// lockstep.
+    pub jitter: f64,
+    /// Longest delay between two attempts in milliseconds, before jitter.
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay_ms: 1000,
+            backoff_factor: 2.0,
+            jitter: 0.1,
+            max_delay_ms: 60_000,
+        }
+    }
+}
+
+/// Returns a pseudo-random number in `[-1, 1]`.
+fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    (hasher.finish() as f64 / u64::MAX as f64) * 2.0 - 1.0
+}
+
+/// Parts of ssh and operating system error messages that point at the
+/// network rather than at keys, host keys or file permissions.
+const NETWORK_MESSAGES: [&str; 8] = [
+    "timed out",
+    "timeout",
+    "connection refused",
+    "connection reset",
+    "connection aborted",
+    "broken pipe",
+    "network is unreachable",
+    "unexpected eof",
+];
+
+/// Whether `error` may go away by simply trying again. Authentication,
+/// certificate and configuration problems never do; ssh and operating
+/// system errors only do when they are about the connection.
+pub fn is_transient(error: &git2::Error) -> bool {
+    match error.code() {
+        ErrorCode::Auth | ErrorCode::Certificate | ErrorCode::NotFound | ErrorCode::Invalid => {
+            false
+        }
+        _ => match error.class() {
+            ErrorClass::Net | ErrorClass::Http => true,
+            ErrorClass::Ssh | ErrorClass::Os => {
+                let message = error.message().to_lowercase();
+                NETWORK_MESSAGES
+                    .iter()
+                    .any(|pattern| message.contains(pattern))
+            }
+            _ => false,
+        },
+    }
+}
+
+impl RetryPolicy {
+    fn max_delay(&self) -> Duration {
+        Duration::from_millis(self.max_delay_ms)
+    }
+
+    /// Delay before retry number `retry`, starting at 1, without jitter and
+    /// at most `max_delay_ms`.
+    fn base_delay(&self, retry: u32) -> Duration {
+        let exponent = i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX);
+        let factor = self.backoff_factor.powi(exponent);
+        Duration::try_from_secs_f64(self.initial_delay_ms as f64 / 1000.0 * factor)
+            .map_or(self.max_delay(), |delay| delay.min(self.max_delay()))
+    }
+
+    fn delay(&self, retry: u32) -> Duration {
+        let base_delay = self.base_delay(retry);
+        let jitter = 1.0 + self.jitter.clamp(0.0, 1.0) * random_unit();
+        Duration::try_from_secs_f64(base_delay.as_secs_f64() * jitter).unwrap_or(base_delay)
+    }
+
+    /// Runs `operation` until it succeeds, fails with a permanent error, or
+    /// `max_attempts` is used up.
+    pub fn run<T, F>(&self, mut operation: F) -> Result<T, git2::Error>
+    where
+        F: FnMut() -> Result<T, git2::Error>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Err(error) if attempt < self.max_attempts && is_transient(&error) => {
+                    let delay = self.delay(attempt);
+                    info!(
+                        "Attempt {} of {} failed, retrying in {:?}: {}",
+                        attempt, self.max_attempts, delay, error
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay_ms: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.base_delay(1), Duration::from_secs(1));
+        assert_eq!(policy.base_delay(3), Duration::from_secs(4));
+        let delay = policy.delay(2);
+        assert!(delay >= Duration::from_millis(1800) && delay <= Duration::from_millis(2200));
+        assert_eq!(policy.base_delay(70), Duration::from_secs(60));
+        assert_eq!(policy.base_delay(u32::MAX), Duration::from_secs(60));
+        let policy = RetryPolicy {
+            initial_delay_ms: u64::MAX,
+            backoff_factor: f64::NAN,
+            jitter: f64::NAN,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(2), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let error = |class, message| git2::Error::new(ErrorCode::GenericError, class, message);
+        assert!(is_transient(&error(ErrorClass::Net, "early EOF")));
+        assert!(is_transient(&error(
+            ErrorClass::Ssh,
+            "Timed out waiting on socket"
+        )));
+        assert!(!is_transient(&error(
+            ErrorClass::Ssh,
+            "Unable to open public key file"
+        )));
+        assert!(!is_transient(&error(
+            ErrorClass::Ssh,
+            "invalid or unknown remote ssh hostkey"
+        )));
+        assert!(!is_transient(&error(
+            ErrorClass::Os,
+            "failed to open file: Permission denied"
+        )));
+    }
+
+    #[test]
+    fn test_retries_transient_errors() {
+        let mut attempts = 0;
+        let result = policy().run(|| {
+            attempts += 1;
+            Err::<(), _>(git2::Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Net,
+                "connection reset",
+            ))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+    }
+
+    #[test]
+    fn test_does_not_retry_auth_errors() {
+        let mut attempts = 0;
+        let result = policy().run(|| {
+            attempts += 1;
+            Err::<(), _>(git2::Error::new(
+                ErrorCode::Auth,
+                ErrorClass::Ssh,
+                "authentication failed",
+            ))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::{canonical_path, GitRepository, Source};
+use anyhow::{bail, Context, Result};
+use glob::Pattern;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A directory that is searched for repositories instead of listing each of
+/// them in `Config::repositories`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ScanRoot {
+    /// Where this entry was written.
+    #[serde(skip)]
+    pub source: Option<Source>,
+    path: PathBuf,
+    /// How many directory levels below `path` to descend.
+    #[serde(default = "default_max_depth")]
+    max_depth: usize,
+    /// Globs matched against the path relative to `path`. If given, only
+    /// matching repositories are used.
+    #[serde(default)]
+    include: Vec<String>,
+    /// Globs matched against the path relative to `path`. Matching
+    /// directories are neither used nor descended into.
+    #[serde(default)]
+    exclude: Vec<String>,
+    /// Remote fetched in discovered repositories, see `GitRepository::remote`.
+    remote: Option<String>,
+    /// Refspecs fetched in discovered repositories, see
+    /// `GitRepository::fetch_branches`.
+    fetch_branches: Option<Vec<String>>,
+}
+
+fn default_max_depth() -> usize {
+    3
+}
+
+fn patterns(globs: &[String]) -> Result<Vec<Pattern>> {
+    globs
+        .iter()
+        .map(|glob| Pattern::new(glob).with_context(|| format!("invalid glob {:?}", glob)))
+        .collect()
+}
+
+fn is_repository(path: &Path) -> bool {
+    path.join(".git").exists()
+}
+
+impl ScanRoot {
+    /// Walks the root and returns every repository found in it.
+    pub fn discover(&self) -> Result<Vec<GitRepository>> {
+        if !self.path.is_dir() {
+            bail!("scan root {:?} is not a directory", self.path);
+        }
+        let include = patterns(&self.include)?;
+        let exclude = patterns(&self.exclude)?;
+        let mut found = Vec::new();
+        let mut pending = vec![(self.path.clone(), 0)];
+        while let Some((directory, depth)) = pending.pop() {
+            let relative = directory.strip_prefix(&self.path).unwrap_or(&directory);
+            if exclude.iter().any(|pattern| pattern.matches_path(relative)) {
+                continue;
+            }
+            if is_repository(&directory) {
+                if include.is_empty()
+                    || include.iter().any(|pattern| pattern.matches_path(relative))
+                {
+                    debug!("Discovered repository {:?}", directory);
+                    found.push(GitRepository {
+                        local_path: directory,
+                        remote: self.remote.clone(),
+                        fetch_branches: self.fetch_branches.clone(),
+                        ..Default::default()
+                    });
+                }
+                continue;
+            }
+            if depth >= self.max_depth {
+                continue;
+            }
+            let entries = match fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    warn!("Cannot scan {:?}: {}", directory, error);
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                if entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false) {
+                    pending.push((entry.path(), depth + 1));
+                }
+            }
+        }
+        found.sort_by(|a, b| a.local_path.cmp(&b.local_path));
+        Ok(found)
+    }
+}
+
+/// Appends the discovered repositories that aren't already listed
+/// explicitly, so explicit entries always win.
+pub fn merge(explicit: &mut Vec<GitRepository>, discovered: Vec<GitRepository>) {
+    let known: HashSet<PathBuf> = explicit
+        .iter()
+        .map(|repository| canonical_path(&repository.local_path))
+        .collect();
+    explicit.extend(
+        discovered
+            .into_iter()
+            .filter(|repository| !known.contains(&canonical_path(&repository.local_path))),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    #[test]
+    fn test_discover() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        for path in &["a", "group/b", "group/c", "vendor/d", "deep/x/y/z/e"] {
+            git2::Repository::init(temp.child(path).path()).unwrap();
+        }
+        let root: ScanRoot = serde_json::from_value(serde_json::json!({
+            "path": temp.path(),
+            "exclude": ["vendor"],
+        }))
+        .unwrap();
+        let discovered = root.discover().unwrap();
+        let paths: Vec<_> = discovered
+            .iter()
+            .map(|repository| repository.local_path.strip_prefix(temp.path()).unwrap())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![Path::new("a"), Path::new("group/b"), Path::new("group/c")]
+        );
+        assert_eq!(discovered[0].remote, None);
+
+        let mut explicit = vec![GitRepository {
+            local_path: temp.child("a").to_path_buf(),
+            remote: Some("upstream".to_string()),
+            ..Default::default()
+        }];
+        merge(&mut explicit, discovered);
+        assert_eq!(explicit.len(), 3);
+        assert_eq!(explicit[0].remote.as_deref(), Some("upstream"));
+    }
+}
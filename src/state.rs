@@ -0,0 +1,407 @@
+use crate::{fetch::FetchOutcome, report::RepositoryResult};
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// How long recorded results may stay in memory before `flush_until` writes
+/// them.
+const WRITE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for another process to release the lock file.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A lock file this old was left behind by a process that died.
+const STALE_LOCK: Duration = Duration::from_secs(60);
+
+/// What is remembered about a repository between runs. Times are seconds
+/// since the Unix epoch.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct RepositoryState {
+    pub last_attempt: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+    /// Why the post-fetch commands failed the last time they ran.
+    pub last_post_fetch_error: Option<String>,
+    pub duration_ms: Option<u64>,
+    /// Target of the remote-tracking refs of the fetched remotes after the
+    /// last fetch.
+    pub refs: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct State {
+    #[serde(default)]
+    repositories: BTreeMap<PathBuf, RepositoryState>,
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Time since `timestamp`, which is in seconds since the Unix epoch.
+pub fn age(timestamp: u64) -> Duration {
+    Duration::from_secs(now().saturating_sub(timestamp))
+}
+
+/// State file to use when the config doesn't name one; `var` looks up
+/// environment variables.
+pub fn default_path(var: impl Fn(&str) -> Option<OsString>) -> Option<PathBuf> {
+    let state_home = var("XDG_STATE_HOME")
+        .filter(|value| !value.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            var("HOME")
+                .filter(|value| !value.is_empty())
+                .map(|home| PathBuf::from(home).join(".local/state"))
+        })?;
+    Some(state_home.join("git-auto-fetch").join("state.json"))
+}
+
+/// Remote-tracking refs of the remotes `outcome` fetched in the repository at
+/// `local_path`, with the object each points to.
+fn ref_tips(
+    local_path: &Path,
+    outcome: &FetchOutcome,
+) -> Result<BTreeMap<String, String>, git2::Error> {
+    let repository = git2::Repository::open(local_path)?;
+    let mut tips = BTreeMap::new();
+    for remote in &outcome.remotes {
+        for reference in repository.references_glob(&format!("refs/remotes/{}/*", remote.name))? {
+            let reference = reference?;
+            if let (Some(name), Some(target)) = (reference.name(), reference.target()) {
+                tips.insert(name.to_string(), target.to_string());
+            }
+        }
+    }
+    Ok(tips)
+}
+
+/// Exclusive access to the state file among processes, held until dropped.
+struct LockFile {
+    path: PathBuf,
+}
+
+impl LockFile {
+    /// Creates `<state file>.lock`, waiting for another process holding it
+    /// and breaking locks that were left behind.
+    fn acquire(state_file: &Path) -> Result<Self> {
+        let mut path = state_file.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let start = Instant::now();
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(LockFile { path }),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    if age.is_some_and(|age| age >= STALE_LOCK) {
+                        warn!("Removing stale lock file {:?}", path);
+                        let _ = fs::remove_file(&path);
+                    } else if start.elapsed() >= LOCK_TIMEOUT {
+                        anyhow::bail!("{:?} is still locked after {:?}", path, LOCK_TIMEOUT);
+                    } else {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                }
+                Err(error) => {
+                    return Err(error).with_context(|| format!("cannot create {:?}", path))
+                }
+            }
+        }
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            warn!("Cannot remove lock file {:?}: {}", self.path, error);
+        }
+    }
+}
+
+/// Why `result` failed, if it did.
+fn error_message(result: &RepositoryResult) -> Option<String> {
+    match &result.result {
+        Ok(outcome) => {
+            let errors: Vec<String> = outcome
+                .remotes
+                .iter()
+                .filter_map(|remote| {
+                    let error = remote.error.as_ref()?;
+                    Some(format!("remote {}: {}", remote.name, error.message()))
+                })
+                .collect();
+            (!errors.is_empty()).then(|| errors.join("; "))
+        }
+        Err(error) => Some(error.message().to_string()),
+    }
+}
+
+/// Why the post-fetch commands of `outcome` failed, if any did.
+fn post_fetch_error_message(outcome: &FetchOutcome) -> Option<String> {
+    let errors: Vec<String> = outcome
+        .failed_post_fetch()
+        .map(|command| format!("{:?}: {:?}", command.command, command.status))
+        .collect();
+    (!errors.is_empty()).then(|| errors.join("; "))
+}
+
+/// The state in memory and what of it isn't written yet.
+struct Memory {
+    state: State,
+    /// Repositories recorded since the last write.
+    changed: BTreeSet<PathBuf>,
+}
+
+/// The state of every repository, kept in a JSON file. Recorded results are
+/// written in batches by `flush`, each time merged into what other processes
+/// wrote meanwhile.
+pub struct StateStore {
+    path: Option<PathBuf>,
+    memory: Mutex<Memory>,
+}
+
+impl StateStore {
+    /// Reads the state from `path`. A missing file is an empty state; an
+    /// unreadable one is set aside as `<path>.corrupt` and replaced. Without a
+    /// path nothing is persisted.
+    pub fn open(path: Option<PathBuf>) -> Self {
+        let state = match &path {
+            Some(path) => Self::read(path),
+            None => {
+                warn!("No state file location, fetch results are not remembered");
+                State::default()
+            }
+        };
+        StateStore {
+            path,
+            memory: Mutex::new(Memory {
+                state,
+                changed: BTreeSet::new(),
+            }),
+        }
+    }
+
+    fn read(path: &Path) -> State {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) => {
+                debug!("No state read from {:?}: {}", path, error);
+                return State::default();
+            }
+        };
+        match serde_json::from_str(&text) {
+            Ok(state) => state,
+            Err(error) => {
+                let mut corrupt = path.as_os_str().to_owned();
+                corrupt.push(".corrupt");
+                warn!(
+                    "Ignoring corrupted state file {:?} ({}), moving it to {:?}",
+                    path, error, corrupt
+                );
+                if let Err(error) = fs::rename(path, &corrupt) {
+                    warn!("Cannot move {:?}: {}", path, error);
+                }
+                State::default()
+            }
+        }
+    }
+
+    /// The state of the repository at `local_path`, if it was ever fetched.
+    pub fn get(&self, local_path: &Path) -> Option<RepositoryState> {
+        self.memory
+            .lock()
+            .unwrap()
+            .state
+            .repositories
+            .get(local_path)
+            .cloned()
+    }
+
+    /// Remembers `result` until the next `flush`.
+    pub fn record(&self, result: &RepositoryResult) {
+        let refs = match &result.result {
+            Ok(outcome) => match ref_tips(&result.local_path, outcome) {
+                Ok(tips) => Some(tips),
+                Err(error) => {
+                    debug!("No refs of {:?}: {}", result.local_path, error);
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+        let mut memory = self.memory.lock().unwrap();
+        let entry = memory
+            .state
+            .repositories
+            .entry(result.local_path.clone())
+            .or_default();
+        let now = now();
+        entry.last_attempt = Some(now);
+        entry.duration_ms = Some(result.duration.as_millis() as u64);
+        entry.last_error = error_message(result);
+        if entry.last_error.is_none() {
+            entry.last_success = Some(now);
+        }
+        if let Some(refs) = refs {
+            entry.refs = refs;
+        }
+        if let Ok(outcome) = &result.result {
+            if !outcome.post_fetch.is_empty() {
+                entry.last_post_fetch_error = post_fetch_error_message(outcome);
+            }
+        }
+        memory.changed.insert(result.local_path.clone());
+    }
+
+    /// Flushes every few seconds until `stop` is disconnected, then once
+    /// more.
+    pub fn flush_until(&self, stop: mpsc::Receiver<()>) {
+        while let Err(mpsc::RecvTimeoutError::Timeout) = stop.recv_timeout(WRITE_INTERVAL) {
+            self.flush();
+        }
+        self.flush();
+    }
+
+    /// Writes the results recorded since the last write.
+    pub fn flush(&self) {
+        let mut memory = self.memory.lock().unwrap();
+        if memory.changed.is_empty() {
+            return;
+        }
+        let path = match &self.path {
+            Some(path) => path,
+            None => {
+                memory.changed.clear();
+                return;
+            }
+        };
+        match Self::merge_into_file(path, &memory) {
+            Ok(state) => {
+                memory.state = state;
+                memory.changed.clear();
+            }
+            Err(error) => warn!("Cannot write state file: {:#}", error),
+        }
+    }
+
+    /// Writes the changed repositories of `memory` into the state file at
+    /// `path`, keeping what other processes wrote there, and returns the
+    /// merged state.
+    fn merge_into_file(path: &Path, memory: &Memory) -> Result<State> {
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)
+                .with_context(|| format!("cannot create {:?}", directory))?;
+        }
+        let _lock = LockFile::acquire(path)?;
+        let mut state = Self::read(path);
+        for local_path in &memory.changed {
+            if let Some(entry) = memory.state.repositories.get(local_path) {
+                state.repositories.insert(local_path.clone(), entry.clone());
+            }
+        }
+        Self::replace(path, &state)?;
+        Ok(state)
+    }
+
+    /// Replaces the state file through a temporary file, so that a crash
+    /// leaves either the old or the new state behind.
+    fn replace(path: &Path, state: &State) -> Result<()> {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(format!(".{}.tmp", std::process::id()));
+        fs::write(&temporary, serde_json::to_vec_pretty(state)?)
+            .with_context(|| format!("cannot write {:?}", temporary))?;
+        fs::rename(&temporary, path).with_context(|| format!("cannot replace {:?}", path))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_fs::prelude::*;
+
+    #[test]
+    fn test_record_and_reopen() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let path = temp.child("state/state.json").to_path_buf();
+        let store = StateStore::open(Some(path.clone()));
+        store.record(&RepositoryResult {
+            local_path: PathBuf::from("/repos/a"),
+            duration: Duration::from_millis(20),
+            result: Err(git2::Error::from_str("unreachable")),
+        });
+        store.flush();
+        let state = StateStore::open(Some(path))
+            .get(Path::new("/repos/a"))
+            .unwrap();
+        assert!(state.last_attempt.is_some());
+        assert_eq!(state.last_success, None);
+        assert_eq!(state.last_error.as_deref(), Some("unreachable"));
+        assert_eq!(state.duration_ms, Some(20));
+    }
+
+    #[test]
+    fn test_concurrent_stores_merge() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let path = temp.child("state.json").to_path_buf();
+        let result = |local_path: &str| RepositoryResult {
+            local_path: PathBuf::from(local_path),
+            duration: Duration::from_millis(1),
+            result: Err(git2::Error::from_str("unreachable")),
+        };
+        let run = StateStore::open(Some(path.clone()));
+        let daemon = StateStore::open(Some(path.clone()));
+        run.record(&result("/repos/a"));
+        daemon.record(&result("/repos/b"));
+        assert!(StateStore::open(Some(path.clone()))
+            .get(Path::new("/repos/b"))
+            .is_none());
+        run.flush();
+        daemon.record(&result("/repos/c"));
+        daemon.flush();
+        let reopened = StateStore::open(Some(path));
+        for local_path in &["/repos/a", "/repos/b", "/repos/c"] {
+            assert!(
+                reopened.get(Path::new(local_path)).is_some(),
+                "{}",
+                local_path
+            );
+        }
+        temp.child("state.json.lock")
+            .assert(predicates::path::missing());
+    }
+
+    #[test]
+    fn test_corrupted_state() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let file = temp.child("state.json");
+        file.write_str("{\"repositories\": {").unwrap();
+        let store = StateStore::open(Some(file.to_path_buf()));
+        assert_eq!(store.get(Path::new("/repos/a")), None);
+        temp.child("state.json.corrupt")
+            .assert("{\"repositories\": {");
+        file.assert(predicates::path::missing());
+    }
+}
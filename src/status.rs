@@ -0,0 +1,88 @@
+use crate::{
+    state::{self, StateStore},
+    GitRepository,
+};
+use git2::BranchType;
+use std::time::Duration;
+
+/// Formats `age` coarsely, e.g. `3d 4h` or `12s`.
+pub fn format_age(age: Duration) -> String {
+    let seconds = age.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+        _ => format!("{}d {}h", seconds / 86400, seconds % 86400 / 3600),
+    }
+}
+
+/// Describes how far each local branch is from its upstream.
+fn branch_lines(repository: &git2::Repository) -> Result<Vec<String>, git2::Error> {
+    let mut lines = Vec::new();
+    for branch in repository.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let upstream = match branch.upstream() {
+            Ok(upstream) => upstream,
+            Err(_) => continue,
+        };
+        let name = branch.name()?.unwrap_or_default().to_string();
+        if let (Some(local), Some(remote)) = (branch.get().target(), upstream.get().target()) {
+            let (ahead, behind) = repository.graph_ahead_behind(local, remote)?;
+            lines.push(format!("{}: ahead {}, behind {}", name, ahead, behind));
+        }
+    }
+    Ok(lines)
+}
+
+/// Prints the recorded fetch results of every repository and how its
+/// branches compare to their upstream.
+pub fn print_status(repositories: &[GitRepository], store: &StateStore) {
+    let ago = |timestamp: Option<u64>| match timestamp {
+        Some(timestamp) => format!("{} ago", format_age(state::age(timestamp))),
+        None => "never".to_string(),
+    };
+    for repository in repositories {
+        println!("{}", repository.local_path.display());
+        match store.get(&repository.local_path) {
+            Some(state) => {
+                println!(
+                    "  last attempt: {} ({} ms)",
+                    ago(state.last_attempt),
+                    state.duration_ms.unwrap_or_default()
+                );
+                println!("  last success: {}", ago(state.last_success));
+                if let Some(error) = &state.last_error {
+                    println!("  last error: {}", error);
+                }
+                if let Some(error) = &state.last_post_fetch_error {
+                    println!("  last post_fetch error: {}", error);
+                }
+            }
+            None => println!("  never fetched"),
+        }
+        let git_repository = match git2::Repository::open(&repository.local_path) {
+            Ok(git_repository) => git_repository,
+            Err(error) => {
+                println!("  not available: {}", error.message());
+                continue;
+            }
+        };
+        match branch_lines(&git_repository) {
+            Ok(lines) => lines.iter().for_each(|line| println!("  {}", line)),
+            Err(error) => println!("  branches: {}", error.message()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(12)), "12s");
+        assert_eq!(format_age(Duration::from_secs(300)), "5m");
+        assert_eq!(format_age(Duration::from_secs(3 * 3600 + 120)), "3h 2m");
+        assert_eq!(format_age(Duration::from_secs(2 * 86400 + 7200)), "2d 2h");
+    }
+}